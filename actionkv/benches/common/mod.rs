@@ -0,0 +1,78 @@
+//! Generated data shared by the benches, pulled in with `mod common;`.
+
+use libactionkv::ByteString;
+
+// keys in each of the ranges from `Dataset::ranges`
+pub const RANGE_WIDTH: usize = 100;
+
+/// Keys and values of a given shape from a seeded xorshift, so every run
+/// measures the same data. Keys are distinct, and share a short prefix like
+/// keys of a real dataset often do.
+pub struct Dataset {
+    pub keys: Vec<ByteString>,
+    pub values: Vec<ByteString>,
+    seed: u64,
+}
+
+impl Dataset {
+    pub fn generate(n: usize, key_len: usize, value_len: usize, seed: u64) -> Dataset {
+        let mut dataset = Dataset {
+            keys: Vec::with_capacity(n),
+            values: Vec::with_capacity(n),
+            seed: seed | 1,
+        };
+        for i in 0..n as u64 {
+            // an odd multiplier makes this a bijection, so no two keys clash
+            let id = format!("user:{:016x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let mut key = id.into_bytes();
+            while key.len() < key_len {
+                key.push(b'a' + (dataset.next() % 26) as u8);
+            }
+            let value = (0..value_len).map(|_| dataset.next() as u8).collect();
+            dataset.keys.push(key);
+            dataset.values.push(value);
+        }
+        dataset
+    }
+
+    /// The number of keys `BENCH_KEYS` asks for, `default` if it's unset,
+    /// and never too few for a range.
+    pub fn size_from_env(default: usize) -> usize {
+        std::env::var("BENCH_KEYS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(default)
+            .max(RANGE_WIDTH + 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed
+    }
+
+    /// `count` keys to look up, in random order.
+    pub fn lookups(&mut self, count: usize) -> Vec<ByteString> {
+        let n = self.keys.len() as u64;
+        (0..count)
+            .map(|_| {
+                let i = (self.next() % n) as usize;
+                self.keys[i].clone()
+            })
+            .collect()
+    }
+
+    /// `count` ranges of `RANGE_WIDTH` keys each.
+    pub fn ranges(&mut self, count: usize) -> Vec<(ByteString, ByteString)> {
+        let mut sorted = self.keys.clone();
+        sorted.sort_unstable();
+        let starts = (sorted.len() - RANGE_WIDTH) as u64;
+        (0..count)
+            .map(|_| {
+                let start = (self.next() % starts) as usize;
+                (sorted[start].clone(), sorted[start + RANGE_WIDTH].clone())
+            })
+            .collect()
+    }
+}
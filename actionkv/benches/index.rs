@@ -0,0 +1,192 @@
+//! Compares the structures a store's index could be kept in, on generated
+//! keys: `cargo bench` measures building the index (insert), point lookups
+//! (get) and range counts (range) for each, reported in elements per
+//! second, and prints a rough figure for the memory it takes. `BENCH_KEYS`
+//! sets the number of keys, 100000 by default.
+
+mod common;
+
+use common::Dataset;
+use criterion::measurement::WallTime;
+use criterion::{
+    criterion_group, criterion_main, BenchmarkGroup, BenchmarkId, Criterion, Throughput,
+};
+use libactionkv::{ActionKV, ByteStr, ByteString};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+use std::mem::size_of;
+use std::ops::Bound;
+
+// ranges counted per iteration of the range case
+const RANGES: usize = 20;
+
+// An index structure under test: key -> record offset.
+trait Variant: Sized {
+    const NAME: &'static str;
+    fn build(keys: &[ByteString]) -> Self;
+    fn get(&mut self, key: &ByteStr) -> Option<u64>;
+    // keys in `start..end`, `None` if the structure can't tell
+    fn range(&mut self, start: &ByteStr, end: &ByteStr) -> Option<usize>;
+    fn memory_bytes(&self) -> usize;
+}
+
+// one slot and control byte per bucket plus the heap buffers of the keys,
+// as `ActionKV::index_memory_bytes` counts them
+fn table_bytes<'a, K: 'a>(capacity: usize, keys: impl Iterator<Item = &'a ByteString>) -> usize {
+    capacity * (size_of::<(K, u64)>() + 1) + keys.map(|key| key.capacity()).sum::<usize>()
+}
+
+impl Variant for HashMap<ByteString, u64> {
+    const NAME: &'static str = "HashMap";
+    fn build(keys: &[ByteString]) -> Self {
+        keys.iter().cloned().zip(0..).collect()
+    }
+    fn get(&mut self, key: &ByteStr) -> Option<u64> {
+        HashMap::get(self, key).cloned()
+    }
+    fn range(&mut self, start: &ByteStr, end: &ByteStr) -> Option<usize> {
+        Some(self.keys().filter(|key| start <= key.as_slice() && key.as_slice() < end).count())
+    }
+    fn memory_bytes(&self) -> usize {
+        table_bytes::<ByteString>(self.capacity(), self.keys())
+    }
+}
+
+impl Variant for BTreeMap<ByteString, u64> {
+    const NAME: &'static str = "BTreeMap";
+    fn build(keys: &[ByteString]) -> Self {
+        keys.iter().cloned().zip(0..).collect()
+    }
+    fn get(&mut self, key: &ByteStr) -> Option<u64> {
+        BTreeMap::get(self, key).cloned()
+    }
+    fn range(&mut self, start: &ByteStr, end: &ByteStr) -> Option<usize> {
+        let bounds = (Bound::Included(start), Bound::Excluded(end));
+        Some(BTreeMap::range::<ByteStr, _>(self, bounds).count())
+    }
+    fn memory_bytes(&self) -> usize {
+        // nodes are at least half full
+        table_bytes::<ByteString>(self.len() * 3 / 2, self.keys())
+    }
+}
+
+// only a 64-bit hash of every key is kept, so ranges can't be answered and
+// a hash collision would go unnoticed
+struct HashedKeys(HashMap<u64, u64>);
+
+fn hash_key(key: &ByteStr) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Variant for HashedKeys {
+    const NAME: &'static str = "hashed keys";
+    fn build(keys: &[ByteString]) -> Self {
+        HashedKeys(keys.iter().map(|key| hash_key(key)).zip(0..).collect())
+    }
+    fn get(&mut self, key: &ByteStr) -> Option<u64> {
+        self.0.get(&hash_key(key)).cloned()
+    }
+    fn range(&mut self, _: &ByteStr, _: &ByteStr) -> Option<usize> {
+        None
+    }
+    fn memory_bytes(&self) -> usize {
+        table_bytes::<u64>(self.0.capacity(), std::iter::empty())
+    }
+}
+
+// the store itself, records and all, in memory
+fn store(keys: &[ByteString], compressed: bool) -> ActionKV<Cursor<Vec<u8>>> {
+    let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+    store.set_compressed_index(compressed);
+    store.set_flush_every_n(1024);
+    for key in keys {
+        store.insert(key, b"v").expect("in-memory insert");
+    }
+    store.flush().expect("in-memory flush");
+    store
+}
+
+impl Variant for ActionKV<Cursor<Vec<u8>>> {
+    const NAME: &'static str = "ActionKV";
+    fn build(keys: &[ByteString]) -> Self {
+        store(keys, false)
+    }
+    fn get(&mut self, key: &ByteStr) -> Option<u64> {
+        self.get(key).expect("in-memory get").map(|value| value.len() as u64)
+    }
+    fn range(&mut self, start: &ByteStr, end: &ByteStr) -> Option<usize> {
+        Some(self.range_count(start, end))
+    }
+    fn memory_bytes(&self) -> usize {
+        self.index_memory_bytes()
+    }
+}
+
+// the store with front-coded keys, see `set_compressed_index`
+struct Compressed(ActionKV<Cursor<Vec<u8>>>);
+
+impl Variant for Compressed {
+    const NAME: &'static str = "compressed";
+    fn build(keys: &[ByteString]) -> Self {
+        Compressed(store(keys, true))
+    }
+    fn get(&mut self, key: &ByteStr) -> Option<u64> {
+        self.0.get(key).expect("in-memory get").map(|value| value.len() as u64)
+    }
+    fn range(&mut self, start: &ByteStr, end: &ByteStr) -> Option<usize> {
+        Some(self.0.range_count(start, end))
+    }
+    fn memory_bytes(&self) -> usize {
+        self.0.index_memory_bytes()
+    }
+}
+
+fn bench<V: Variant>(group: &mut BenchmarkGroup<'_, WallTime>, dataset: &mut Dataset) {
+    let keys = &dataset.keys;
+    group.throughput(Throughput::Elements(keys.len() as u64));
+    group.bench_function(BenchmarkId::new("insert", V::NAME), |b| b.iter(|| V::build(keys)));
+
+    let mut index = V::build(&dataset.keys);
+    let lookups = dataset.lookups(dataset.keys.len());
+    group.throughput(Throughput::Elements(lookups.len() as u64));
+    group.bench_function(BenchmarkId::new("get", V::NAME), |b| {
+        b.iter(|| lookups.iter().filter_map(|key| index.get(key)).count())
+    });
+
+    let ranges = dataset.ranges(RANGES);
+    if index.range(&ranges[0].0, &ranges[0].1).is_some() {
+        group.throughput(Throughput::Elements(ranges.len() as u64));
+        group.bench_function(BenchmarkId::new("range", V::NAME), |b| {
+            b.iter(|| {
+                ranges
+                    .iter()
+                    .filter_map(|(start, end)| index.range(start, end))
+                    .sum::<usize>()
+            })
+        });
+    }
+    println!("{:<12} {:<7} {:>14} bytes", V::NAME, "memory", index.memory_bytes());
+}
+
+fn index(c: &mut Criterion) {
+    let n = Dataset::size_from_env(100_000);
+    let mut dataset = Dataset::generate(n, 32, 16, 0x5eed);
+    println!("{} keys of {} bytes", n, dataset.keys[0].len());
+
+    let mut group = c.benchmark_group("index");
+    // a whole index per iteration, so fewer samples than the default 100
+    group.sample_size(10);
+    bench::<HashMap<ByteString, u64>>(&mut group, &mut dataset);
+    bench::<BTreeMap<ByteString, u64>>(&mut group, &mut dataset);
+    bench::<HashedKeys>(&mut group, &mut dataset);
+    bench::<ActionKV<Cursor<Vec<u8>>>>(&mut group, &mut dataset);
+    bench::<Compressed>(&mut group, &mut dataset);
+    group.finish();
+}
+
+criterion_group!(benches, index);
+criterion_main!(benches);
@@ -1,4 +1,12 @@
-use libactionkv::ActionKV;
+use libactionkv::{decode_record_with, ActionKV, CrcVariant, KeyDisplay};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+// how often `follow` looks for new records
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 #[cfg(target_os = "windows")]
 const USAGE: &str = "
@@ -7,6 +15,7 @@ USAGE:
     akv_mem.exe FILE delete KEY
     akv_mem.exe FILE insert KEY
     akv_mem.exe FILE update KEY
+    akv_mem.exe FILE follow
 ";
 
 #[cfg(not(target_os = "windows"))]
@@ -16,14 +25,91 @@ USAGE:
     akv_mem FILE delete KEY
     akv_mem FILE insert KEY
     akv_mem FILE update KEY
+    akv_mem FILE follow
 ";
 
+#[cfg(unix)]
+fn file_id(meta: &fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (meta.dev(), meta.ino())
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &fs::Metadata) -> (u64, u64) {
+    (0, 0)
+}
+
+// the file at `path`, its checksum variant and where it currently ends
+fn open_at_end(path: &Path) -> io::Result<(File, CrcVariant, u64)> {
+    let crc = ActionKV::open_existing(path)?.crc_variant();
+    let mut f = File::open(path)?;
+    let end = f.seek(SeekFrom::End(0))?;
+    Ok((f, crc, end))
+}
+
+// Prints records as other processes append them, like `tail -f`. Records
+// already in the file are skipped. When the file is replaced, as compaction
+// does, or shrinks, the new file is followed from its end on.
+fn follow(path: &Path) -> io::Result<()> {
+    let (mut f, mut crc, mut position) = open_at_end(path)?;
+    eprintln!("following {} from offset {}", path.display(), position);
+
+    let mut tail = Vec::new();
+    loop {
+        let len = f.metadata()?.len();
+        if len == position {
+            // only once the old file is read to the end
+            let replaced = match fs::metadata(path) {
+                Ok(meta) => file_id(&meta) != file_id(&f.metadata()?),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+                Err(err) => return Err(err),
+            };
+            if !replaced {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+        }
+        if len <= position {
+            (f, crc, position) = open_at_end(path)?;
+            tail.clear();
+            eprintln!("reopened {} at offset {}", path.display(), position);
+            continue;
+        }
+        f.seek(SeekFrom::Start(position))?;
+        let n = (&mut f).take(len - position).read_to_end(&mut tail)?;
+        position += n as u64;
+
+        // a record can arrive in pieces, so keep the unfinished rest
+        loop {
+            let kv = match decode_record_with(crc, &tail) {
+                Ok(kv) => kv,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            };
+            let record_len = 12 + kv.key.len() + kv.value.len(); // header + data
+            tail.drain(..record_len);
+            if kv.key.is_empty() {
+                continue; // filler
+            }
+            if kv.value.is_empty() {
+                println!("{} deleted", KeyDisplay(&kv.key));
+            } else {
+                println!("{} {:?}", KeyDisplay(&kv.key), kv.value);
+            }
+        }
+    }
+}
+
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    let fname = args.get(1).expect(&USAGE);
+    let fname = args.get(1).expect(USAGE);
 
-    let action = args.get(2).expect(&USAGE).as_ref();
-    let key = args.get(3).expect(&USAGE).as_ref();
+    let action = args.get(2).expect(USAGE).as_ref();
+    if action == "follow" {
+        follow(Path::new(&fname)).expect("unable to follow file");
+        return;
+    }
+    let key = args.get(3).expect(USAGE).as_ref();
 
     let maybe_value = args.get(4);
 
@@ -33,16 +119,16 @@ fn main() {
 
     match action {
         "get" => match store.get(key).unwrap() {
-            None => eprintln!("{:?} not found", key),
+            None => eprintln!("{} not found", KeyDisplay(key)),
             Some(value) => eprintln!("{:?}", value),
         },
         "delete" => store.delete(key).unwrap(),
         "insert" => {
-            let value = maybe_value.expect(&USAGE).as_ref();
+            let value = maybe_value.expect(USAGE).as_ref();
             store.update(key, value).unwrap();
         },
         "update" => {
-            let value = maybe_value.expect(&USAGE).as_ref();
+            let value = maybe_value.expect(USAGE).as_ref();
             store.update(key, value).unwrap();
         },
         _ => eprintln!("{}", USAGE),
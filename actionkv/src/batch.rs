@@ -0,0 +1,297 @@
+use super::*;
+use crate::record::{encode_tagged_filler, TAGGED_FILLER};
+
+// payloads of the fillers that frame the records of a `commit_batch`; the
+// opening one is followed by the length of the records in between
+const BEGIN_TAG: &[u8] = b"batch:";
+const COMMIT_TAG: &[u8] = b"commit";
+
+const BEGIN_LEN: u64 = HEADER_LEN + BEGIN_TAG.len() as u64 + 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Marker {
+    // the batch's records take up this many bytes after the marker
+    Begin(u64),
+    Commit,
+}
+
+fn encode_marker(marker: Marker, buf: &mut Vec<u8>) {
+    let mut payload = Vec::new();
+    match marker {
+        Marker::Begin(len) => {
+            payload.extend_from_slice(BEGIN_TAG);
+            payload.extend_from_slice(&len.to_le_bytes());
+        }
+        Marker::Commit => payload.extend_from_slice(COMMIT_TAG),
+    }
+    encode_tagged_filler(&payload, buf);
+}
+
+// The marker a record with this checksum, key and value is, if any. Only
+// tagged fillers count, never a freed record that happens to hold the bytes.
+pub(crate) fn marker_of(checksum: u32, key: &ByteStr, value: &ByteStr) -> Option<Marker> {
+    if !key.is_empty() || checksum != TAGGED_FILLER {
+        return None;
+    }
+    if value == COMMIT_TAG {
+        return Some(Marker::Commit);
+    }
+    let len = value.strip_prefix(BEGIN_TAG)?.try_into().ok()?;
+    Some(Marker::Begin(u64::from_le_bytes(len)))
+}
+
+// A batch `load` has seen the opening marker of; its records only count
+// once the commit marker shows up right after them.
+pub(crate) struct OpenBatch {
+    pub start: u64,
+    pub end: u64,
+    pub records: Vec<(KeyValuePair, u64)>,
+}
+
+impl OpenBatch {
+    pub fn new(start: u64, len: u64) -> OpenBatch {
+        OpenBatch {
+            start,
+            end: start + BEGIN_LEN + len,
+            records: Vec::new(),
+        }
+    }
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// Writes a batch that survives a crash either whole or not at all. The
+    /// records go out in one append, framed by a marker before and a commit
+    /// marker after them; `load` drops a batch whose commit marker is
+    /// missing and cuts it off the end of the file. An empty value deletes
+    /// the key, as in `insert`. The batch is held to the same write limit,
+    /// alignment and `max_file_size` as the records of `insert`; one that
+    /// would grow the file past the limit is not written at all.
+    pub fn commit_batch(&mut self, pairs: &[(ByteString, ByteString)]) -> io::Result<()> {
+        if pairs.iter().any(|(key, _)| key.is_empty()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty keys are reserved for filler records",
+            ));
+        }
+        self.check_file()?;
+        if self.free_list.is_some() {
+            self.check_in_place()?;
+        }
+        if let Some(throttle) = &mut self.throttle {
+            let lens = pairs.iter().map(|(key, value)| key.len() + value.len());
+            throttle.take(lens.map(|len| HEADER_LEN + len as u64).sum())?;
+        }
+        let mut start = self.seek_to_end()?;
+        let (mut buf, mut positions) = self.encode_batch(start, pairs);
+
+        if let Some(limit) = self.max_file_size {
+            if start + buf.len() as u64 > limit {
+                if let Some(compact) = self.compact_when_full {
+                    compact(self)?;
+                    start = self.seek_to_end()?;
+                    (buf, positions) = self.encode_batch(start, pairs);
+                }
+                if start + buf.len() as u64 > limit {
+                    return Err(io::Error::other(format!(
+                        "a batch of {} bytes would grow the file past its limit of {} bytes",
+                        buf.len(),
+                        limit
+                    )));
+                }
+            }
+        }
+
+        if let Err(err) = self.f.write_all(&buf) {
+            let _ = self.f.set_len(start);
+            return Err(err);
+        }
+
+        for ((key, value), position) in pairs.iter().zip(positions.iter().copied()) {
+            let old = if value.is_empty() {
+                self.index.remove(key)
+            } else {
+                self.index.insert(key.clone(), position)
+            };
+            if self.free_list.is_some() {
+                if let Some(old) = old {
+                    self.free_record(old)?;
+                }
+                if value.is_empty() {
+                    self.free_record(position)?;
+                }
+            }
+        }
+        // only once the index has all of the batch, in case the log saves it
+        let end = start + buf.len() as u64;
+        for (i, ((key, value), position)) in pairs.iter().zip(positions).enumerate() {
+            let logged_end = if i + 1 == pairs.len() { end } else { start };
+            self.log_index_at(key, Some(position).filter(|_| !value.is_empty()), logged_end)?;
+        }
+        Ok(())
+    }
+
+    // The bytes a batch appended at `start` takes up, markers and alignment
+    // fillers included, and the offsets its records end up at.
+    fn encode_batch(&self, start: u64, pairs: &[(ByteString, ByteString)]) -> (Vec<u8>, Vec<u64>) {
+        let mut buf = Vec::new();
+        if start == 0 {
+            self.crc.encode_header(&mut buf);
+        }
+        let position = start + buf.len() as u64;
+        let padding = padding_for(position, self.alignment);
+        if padding > 0 {
+            encode_filler(padding, &mut buf);
+        }
+        // the records, and the commit marker after them, are aligned too
+        let records_start = position + padding + BEGIN_LEN;
+        let mut records = Vec::new();
+        let mut positions = Vec::with_capacity(pairs.len());
+        let align = |records: &mut Vec<u8>| {
+            let padding = padding_for(records_start + records.len() as u64, self.alignment);
+            if padding > 0 {
+                encode_filler(padding, records);
+            }
+            records_start + records.len() as u64
+        };
+        for (key, value) in pairs {
+            positions.push(align(&mut records));
+            encode_record_with(self.crc, key, value, &mut records);
+        }
+        align(&mut records);
+        encode_marker(Marker::Begin(records.len() as u64), &mut buf);
+        buf.extend_from_slice(&records);
+        encode_marker(Marker::Commit, &mut buf);
+        (buf, positions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_db;
+
+    fn batch(prefix: &str) -> Vec<(ByteString, ByteString)> {
+        (0..5)
+            .map(|i| (format!("{}{}", prefix, i).into_bytes(), b"v".to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn committed_batches_survive_reopen() {
+        let path = temp_db("batch");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert("single", b"1").unwrap();
+        store.commit_batch(&batch("a")).unwrap();
+        store.commit_batch(&[(b"a0".to_vec(), Vec::new())]).unwrap();
+        store.insert("after", b"2").unwrap();
+
+        let mut reopened = ActionKV::open(&path).unwrap();
+        reopened.load().unwrap();
+        assert_eq!(reopened.index, store.index);
+        assert_eq!(reopened.get("a4").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(reopened.get("a0").unwrap(), None);
+
+        let mut parallel = ActionKV::open(&path).unwrap();
+        parallel.rebuild_index_parallel().unwrap();
+        assert_eq!(parallel.index, store.index);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn batches_without_their_commit_marker_are_dropped() {
+        let path = temp_db("batch-torn");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.commit_batch(&batch("a")).unwrap();
+        let committed = store.seek_to_end().unwrap();
+        store.commit_batch(&batch("b")).unwrap();
+        drop(store);
+
+        // crash after the records but before the commit marker
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() - (HEADER_LEN as usize + COMMIT_TAG.len()));
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut parallel = ActionKV::open(&path).unwrap();
+        parallel.rebuild_index_parallel().unwrap();
+        assert!(parallel.contains_key("a4"));
+        assert!(!parallel.contains_key("b0"));
+        drop(parallel);
+
+        std::fs::write(&path, &bytes).unwrap();
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 5);
+        assert!(!store.contains_key("b0"));
+        assert_eq!(store.seek_to_end().unwrap(), committed);
+
+        store.insert("c", b"3").unwrap();
+        let mut reopened = ActionKV::open(&path).unwrap();
+        reopened.load().unwrap();
+        assert_eq!(reopened.index.len(), 6);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn freed_records_never_pass_for_markers() {
+        let path = temp_db("batch-lookalike");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_free_list(true).unwrap();
+        store.load().unwrap();
+        // key and value together read like the opening marker of a batch
+        store.insert(b"batch:01", b"abcdef").unwrap();
+        for i in 0..10u64 {
+            store.insert_u64(i, i).unwrap();
+        }
+        store.delete(b"batch:01").unwrap();
+        let len = store.seek_to_end().unwrap();
+        drop(store);
+
+        let mut reopened = ActionKV::open(&path).unwrap();
+        reopened.load().unwrap();
+        assert_eq!(reopened.index.len(), 10);
+        assert_eq!(reopened.get_u64(9).unwrap(), Some(9));
+        assert_eq!(reopened.seek_to_end().unwrap(), len);
+        let mut parallel = ActionKV::open(&path).unwrap();
+        parallel.rebuild_index_parallel().unwrap();
+        assert_eq!(parallel.index, reopened.index);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn batches_past_the_size_limit_are_refused() {
+        let path = temp_db("batch-limit");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert("single", b"1").unwrap();
+        let len = store.seek_to_end().unwrap();
+        store.set_max_file_size(Some(len + 100));
+
+        let big: Vec<_> = (0..10u8).map(|i| (vec![b'k', i], vec![i; 8])).collect();
+        let err = store.commit_batch(&big).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(store.seek_to_end().unwrap(), len);
+        assert_eq!(store.index.len(), 1);
+
+        store.commit_batch(&big[..2]).unwrap();
+        assert!(store.seek_to_end().unwrap() <= len + 100);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn batches_keep_the_alignment() {
+        let path = temp_db("batch-aligned");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_alignment(16);
+        store.insert("single", b"1").unwrap();
+        store.commit_batch(&batch("a")).unwrap();
+        store.insert("after", b"2").unwrap();
+        assert!(store.index.values().all(|position| position % 16 == 0));
+
+        let mut reopened = ActionKV::open(&path).unwrap();
+        reopened.load().unwrap();
+        assert_eq!(reopened.index, store.index);
+        let mut parallel = ActionKV::open(&path).unwrap();
+        parallel.rebuild_index_parallel().unwrap();
+        assert_eq!(parallel.index, store.index);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,515 @@
+use super::*;
+use std::fs;
+use std::thread::{self, JoinHandle};
+
+/// A compaction running on a background thread, see `start_compaction`.
+#[derive(Debug)]
+pub struct Compaction {
+    worker: JoinHandle<io::Result<Index>>,
+    tmp_path: PathBuf,
+    snapshot_end: u64,
+}
+
+/// What `compact` would achieve right now, see `compact_dry_run`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactEstimate {
+    /// Bytes taken up by the records the index points at.
+    pub live_bytes: u64,
+    /// Bytes of overwritten and deleted records, fillers and tombstones.
+    pub dead_bytes: u64,
+    /// Size of the data file after compaction.
+    pub new_file_size: u64,
+}
+
+impl ActionKV {
+    /// Works out how much `compact` would save without writing anything.
+    /// Only the headers of live records are read.
+    pub fn compact_dry_run(&mut self) -> io::Result<CompactEstimate> {
+        let file_len = self.seek_to_end()?;
+        let mut header = Vec::new();
+        self.crc.encode_header(&mut header);
+
+        let mut live_bytes = 0;
+        for position in self.index.values() {
+            self.f.seek(SeekFrom::Start(position + 4))?;
+            let key_len = self.f.read_u32::<LittleEndian>()? as u64;
+            let val_len = self.f.read_u32::<LittleEndian>()? as u64;
+            live_bytes += HEADER_LEN + key_len + val_len;
+        }
+        let new_file_size = header.len() as u64 + live_bytes;
+        Ok(CompactEstimate {
+            live_bytes,
+            dead_bytes: file_len.saturating_sub(new_file_size),
+            new_file_size,
+        })
+    }
+
+    /// Compacts the file before giving up on an append that would go past
+    /// `set_max_file_size`, so the limit counts live data rather than dead
+    /// records. The append still fails if compacting doesn't free enough.
+    pub fn set_compact_when_full(&mut self, enabled: bool) {
+        self.compact_when_full = if enabled { Some(ActionKV::compact) } else { None };
+    }
+
+    /// Rewrites the live records into a fresh file and swaps it in.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let compaction = self.start_compaction()?;
+        self.finish_compaction(compaction)
+    }
+
+    /// Writes the live records, in file order, into a fresh data file at
+    /// `dest` and returns its size; the store itself is left as it is. After
+    /// each record `cb(records_done, records_total)` is called, and returning
+    /// `false` stops the copy, removes `dest` and fails with `Interrupted`.
+    pub fn compact_with<F: FnMut(u64, u64) -> bool>(
+        &mut self,
+        dest: &Path,
+        mut cb: F,
+    ) -> io::Result<u64> {
+        self.check_file()?;
+        self.flush()?;
+        let mut live: Vec<u64> = self.index.values().collect();
+        live.sort_unstable();
+
+        let written = self.write_compacted(dest, &live, &mut cb);
+        if written.is_err() {
+            let _ = fs::remove_file(dest);
+        }
+        written
+    }
+
+    fn write_compacted<F: FnMut(u64, u64) -> bool>(
+        &mut self,
+        dest: &Path,
+        live: &[u64],
+        cb: &mut F,
+    ) -> io::Result<u64> {
+        let mut out = BufWriter::new(File::create(dest)?);
+        let mut header = Vec::new();
+        self.crc.encode_header(&mut header);
+        out.write_all(&header)?;
+        let mut written = header.len() as u64;
+
+        let total = live.len() as u64;
+        for (done, &position) in live.iter().enumerate() {
+            let kv = self.get_at(position)?;
+            write_record(&mut out, self.crc, &kv.key, &kv.value)?;
+            written += HEADER_LEN + (kv.key.len() + kv.value.len()) as u64;
+            if !cb(done as u64 + 1, total) {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "compaction cancelled"));
+            }
+        }
+        out.flush()?;
+        out.get_ref().sync_all()?;
+        Ok(written)
+    }
+
+    /// Starts copying the records that are live right now into a new file on
+    /// a background thread with its own file handle. The store stays fully
+    /// usable meanwhile; writes made before `finish_compaction` end up in the
+    /// compacted file too.
+    ///
+    /// Unavailable with the free list or `set_overwrite_in_place`, whose
+    /// in-place writes can't be told apart from the snapshot.
+    pub fn start_compaction(&mut self) -> io::Result<Compaction> {
+        if self.free_list.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "compaction can't run with the free list enabled",
+            ));
+        }
+        if self.overwrite_in_place {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "compaction can't run while values are overwritten in place",
+            ));
+        }
+
+        let snapshot_end = self.seek_to_end()?;
+        let mut live: Vec<(ByteString, u64)> =
+            self.index.iter().map(|(k, v)| (k.into_owned(), v)).collect();
+        live.sort_by_key(|&(_, position)| position);
+
+        debug!(
+            "compaction started: {} live keys in {} bytes",
+            live.len(),
+            snapshot_end
+        );
+        let mut index = self.index.empty_like(live.len());
+        let crc = self.crc;
+        let src_path = self.path()?.to_path_buf();
+        let tmp_path = src_path.with_extension("compact");
+        let dest_path = tmp_path.clone();
+        let worker = thread::spawn(move || {
+            let mut src = BufReader::new(File::open(src_path)?);
+            let mut dest = BufWriter::new(File::create(dest_path)?);
+            let mut header = Vec::new();
+            crc.encode_header(&mut header);
+            dest.write_all(&header)?;
+            let mut position = header.len() as u64;
+            for (key, old) in live {
+                src.seek(SeekFrom::Start(old))?;
+                let kv = process_record(&mut src, crc)?;
+                write_record(&mut dest, crc, &kv.key, &kv.value)?;
+                index.insert(key, position);
+                position += HEADER_LEN + (kv.key.len() + kv.value.len()) as u64;
+            }
+            dest.flush()?;
+            Ok(index)
+        });
+
+        Ok(Compaction {
+            worker,
+            tmp_path,
+            snapshot_end,
+        })
+    }
+
+    /// Waits for the background copy, appends whatever was written since it
+    /// started and atomically replaces the data file with the compacted one.
+    pub fn finish_compaction(&mut self, compaction: Compaction) -> io::Result<()> {
+        let Compaction {
+            worker,
+            tmp_path,
+            snapshot_end,
+        } = compaction;
+        let copied = worker
+            .join()
+            .map_err(|_| io::Error::other("compaction thread panicked"))?;
+        let mut index = match copied {
+            Ok(index) => index,
+            Err(err) => {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(err);
+            }
+        };
+
+        // keys written after the snapshot live past its end, keys deleted
+        // since are missing from the current index and need a tombstone
+        let mut tail: Vec<(ByteString, u64)> = self
+            .index
+            .iter()
+            .filter(|&(_, position)| position >= snapshot_end)
+            .map(|(k, v)| (k.into_owned(), v))
+            .collect();
+        tail.sort_by_key(|&(_, position)| position);
+        let deleted: Vec<ByteString> = index
+            .keys()
+            .filter(|key| !self.index.contains_key(key))
+            .map(Cow::into_owned)
+            .collect();
+
+        let mut dest = OpenOptions::new().write(true).open(&tmp_path)?;
+        let mut position = dest.seek(SeekFrom::End(0))?;
+        {
+            let mut w = BufWriter::new(&mut dest);
+            for key in deleted {
+                write_record(&mut w, self.crc, &key, b"")?;
+                index.remove(&key);
+                position += HEADER_LEN + key.len() as u64;
+            }
+            for (key, old) in tail {
+                let kv = self.get_at(old)?;
+                write_record(&mut w, self.crc, &kv.key, &kv.value)?;
+                index.insert(key, position);
+                position += HEADER_LEN + (kv.key.len() + kv.value.len()) as u64;
+            }
+            w.flush()?;
+        }
+        dest.sync_all()?;
+
+        let old_len = self.seek_to_end()?;
+        let path = self.path()?.to_path_buf();
+        fs::rename(&tmp_path, &path)?;
+        self.f = self.open_own_file(&path)?;
+        self.file_id = file_id(&self.f.metadata()?);
+        self.index = index;
+        debug!(
+            "compaction finished: {} keys in {} bytes, {} bytes reclaimed",
+            self.index.len(),
+            position,
+            old_len.saturating_sub(position)
+        );
+        if self.index_log.is_some() {
+            self.save_index()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{file_len, temp_db};
+    use proptest::prelude::*;
+    use std::sync::{Mutex, Once};
+    use std::thread::ThreadId;
+
+    // keeps every message along with the thread that logged it, so tests
+    // running side by side only look at their own
+    struct CapturingLogger(Mutex<Vec<(ThreadId, String)>>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let line = (thread::current().id(), record.args().to_string());
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+
+    fn logged_here() -> Vec<String> {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&LOGGER).unwrap();
+            log::set_max_level(log::LevelFilter::Debug);
+        });
+        let me = thread::current().id();
+        let lines = LOGGER.0.lock().unwrap();
+        lines.iter().filter(|(id, _)| *id == me).map(|(_, line)| line.clone()).collect()
+    }
+
+    #[test]
+    fn compaction_drops_dead_records() {
+        let path = temp_db("compact");
+        let mut store = ActionKV::open(&path).unwrap();
+        for i in 0..10u64 {
+            store.insert_u64(i, i).unwrap();
+            store.insert_u64(i, i + 1).unwrap();
+        }
+        store.delete(3u64.to_be_bytes()).unwrap();
+        let before = file_len(&path);
+
+        logged_here();
+        store.compact().unwrap();
+        let lines = logged_here();
+        assert!(lines.iter().any(|line| line.starts_with("compaction started")));
+        assert!(lines.iter().any(|line| line.starts_with("compaction finished")));
+        assert!(file_len(&path) < before);
+        assert_eq!(store.get_u64(3).unwrap(), None);
+        assert_eq!(store.get_u64(7).unwrap(), Some(8));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dry_run_predicts_the_compacted_size() {
+        let path = temp_db("compact-dry-run");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_crc_variant(CrcVariant::Castagnoli);
+        for i in 0..20u64 {
+            store.insert_u64(i, i).unwrap();
+        }
+        for i in 0..10u64 {
+            store.insert(i.to_be_bytes(), b"a longer value than before").unwrap();
+        }
+        store.delete(15u64.to_be_bytes()).unwrap();
+        let before = file_len(&path);
+
+        let estimate = store.compact_dry_run().unwrap();
+        assert_eq!(file_len(&path), before);
+        store.compact().unwrap();
+        assert_eq!(estimate.new_file_size, file_len(&path));
+        assert_eq!(estimate.dead_bytes, before - file_len(&path));
+        assert_eq!(estimate.live_bytes, 10 * (12 + 8 + 26) + 9 * (12 + 8 + 8));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compact_with_reports_progress_and_can_be_cancelled() {
+        let path = temp_db("compact-with");
+        let dest = temp_db("compact-with-out");
+        let mut store = ActionKV::open(&path).unwrap();
+        for i in 0..10u64 {
+            store.insert_u64(i, i).unwrap();
+            store.insert_u64(i, i + 1).unwrap();
+        }
+        let original = std::fs::read(&path).unwrap();
+
+        let mut seen = Vec::new();
+        let err = store
+            .compact_with(&dest, |done, total| {
+                seen.push((done, total));
+                done < 4
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(seen, vec![(1, 10), (2, 10), (3, 10), (4, 10)]);
+        assert!(!dest.exists());
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+        assert_eq!(store.get_u64(9).unwrap(), Some(10));
+
+        let mut calls = 0;
+        let written = store
+            .compact_with(&dest, |_, _| {
+                calls += 1;
+                true
+            })
+            .unwrap();
+        assert_eq!(calls, 10);
+        assert_eq!(written, file_len(&dest));
+        assert!(written < original.len() as u64);
+        let mut compacted = ActionKV::open(&dest).unwrap();
+        compacted.load().unwrap();
+        assert_eq!(compacted.get_u64(9).unwrap(), Some(10));
+        assert_eq!(compacted.index.len(), 10);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&dest).unwrap();
+    }
+
+    #[test]
+    fn full_files_compact_and_then_refuse_appends() {
+        let path = temp_db("compact-when-full");
+        let mut store = ActionKV::open(&path).unwrap();
+        let record_len = 12 + 8 + 8;
+        store.set_max_file_size(Some(10 * record_len));
+        store.set_compact_when_full(true);
+
+        // rewriting a few keys over and over never fills the file up
+        for round in 0..20u64 {
+            for i in 0..3u64 {
+                store.insert_u64(i, round).unwrap();
+            }
+        }
+        assert!(file_len(&path) <= 10 * record_len);
+        assert_eq!(store.get_u64(2).unwrap(), Some(19));
+
+        // live data does
+        for i in 3..10u64 {
+            store.insert_u64(i, i).unwrap();
+        }
+        let full = file_len(&path);
+        let err = store.insert_u64(10, 10).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(file_len(&path), full);
+        assert_eq!(store.get_u64(10).unwrap(), None);
+        assert_eq!(store.get_u64(9).unwrap(), Some(9));
+
+        store.set_max_file_size(None);
+        store.insert_u64(10, 10).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_during_compaction_are_kept() {
+        let path = temp_db("compact-concurrent");
+        let mut store = ActionKV::open(&path).unwrap();
+        for i in 0..200u64 {
+            store.insert_u64(i, i).unwrap();
+        }
+
+        let compaction = store.start_compaction().unwrap();
+        for i in 0..50u64 {
+            store.insert_u64(i, i * 10).unwrap();
+        }
+        for i in 200..250u64 {
+            store.insert_u64(i, i).unwrap();
+        }
+        store.delete(100u64.to_be_bytes()).unwrap();
+        store.finish_compaction(compaction).unwrap();
+
+        let check = |store: &mut ActionKV| {
+            assert_eq!(store.index.len(), 249);
+            assert_eq!(store.get_u64(100).unwrap(), None);
+            for i in 0..250u64 {
+                let expected = match i {
+                    100 => continue,
+                    0..=49 => i * 10,
+                    _ => i,
+                };
+                assert_eq!(store.get_u64(i).unwrap(), Some(expected));
+            }
+        };
+        check(&mut store);
+
+        let mut reopened = ActionKV::open(&path).unwrap();
+        reopened.load().unwrap();
+        check(&mut reopened);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        // an empty value deletes, as with `insert` itself
+        Insert(u8, ByteString),
+        Update(u8, ByteString),
+        Delete(u8),
+        Compact,
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        let key = 0..8u8;
+        let value = proptest::collection::vec(any::<u8>(), 0..16);
+        prop_oneof![
+            4 => (key.clone(), value.clone()).prop_map(|(k, v)| Op::Insert(k, v)),
+            2 => (key.clone(), value).prop_map(|(k, v)| Op::Update(k, v)),
+            2 => key.prop_map(Op::Delete),
+            1 => Just(Op::Compact),
+        ]
+    }
+
+    fn apply(store: &mut ActionKV, model: &mut HashMap<ByteString, ByteString>, op: &Op) {
+        match op {
+            Op::Insert(k, v) | Op::Update(k, v) => {
+                if let Op::Insert(..) = op {
+                    store.insert([*k], v).unwrap();
+                } else {
+                    store.update([*k], v).unwrap();
+                }
+                if v.is_empty() {
+                    model.remove(&vec![*k]);
+                } else {
+                    model.insert(vec![*k], v.clone());
+                }
+            }
+            Op::Delete(k) => {
+                store.delete([*k]).unwrap();
+                model.remove(&vec![*k]);
+            }
+            Op::Compact => store.compact().unwrap(),
+        }
+    }
+
+    fn assert_matches(store: &mut ActionKV, model: &HashMap<ByteString, ByteString>) {
+        assert_eq!(store.index.len(), model.len());
+        for k in 0..8u8 {
+            assert_eq!(store.get([k]).unwrap().as_ref(), model.get(&vec![k]));
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        // ops before the compaction starts, ops while it runs in the
+        // background; compactions among the first ones run to completion
+        #[test]
+        fn compaction_keeps_what_a_map_would(
+            before in proptest::collection::vec(op(), 0..40),
+            during in proptest::collection::vec(op(), 0..20),
+        ) {
+            let path = temp_db("compact-model");
+            let mut store = ActionKV::open(&path).unwrap();
+            let mut model = HashMap::new();
+            for op in &before {
+                apply(&mut store, &mut model, op);
+            }
+
+            let compaction = store.start_compaction().unwrap();
+            for op in during.iter().filter(|op| !matches!(op, Op::Compact)) {
+                apply(&mut store, &mut model, op);
+            }
+            store.finish_compaction(compaction).unwrap();
+            assert_matches(&mut store, &model);
+
+            let mut reopened = ActionKV::open(&path).unwrap();
+            reopened.load().unwrap();
+            assert_matches(&mut reopened, &model);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
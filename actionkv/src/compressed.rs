@@ -0,0 +1,362 @@
+use super::*;
+use std::ops::Bound;
+
+// keys per block once a full block is split; blocks hold up to twice as many
+const BLOCK: usize = 16;
+
+// Index entries with front-coded keys: grouped into blocks of neighbouring
+// keys, each block filed under its first key, and every later key of a
+// block stored as the length it shares with the key before it plus the
+// rest. Keys with long common prefixes take a fraction of the memory of a
+// `HashMap`, at the price of lookups that search the block map and then
+// decode up to a block's worth of keys, and of writes that re-encode the
+// block they land in.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CompressedIndex {
+    blocks: BTreeMap<ByteString, Block>,
+    len: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Block {
+    // (shared: u32, suffix_len: u32, suffix) for every key after the first
+    data: ByteString,
+    // record offsets of all keys of the block, the first one included
+    positions: Vec<u64>,
+}
+
+impl Block {
+    fn encode(entries: &[(ByteString, u64)]) -> Block {
+        let mut block = Block {
+            data: ByteString::new(),
+            positions: entries.iter().map(|&(_, position)| position).collect(),
+        };
+        for pair in entries.windows(2) {
+            let (previous, key) = (&pair[0].0, &pair[1].0);
+            let shared = previous.iter().zip(key.iter()).take_while(|(a, b)| a == b).count();
+            let suffix = &key[shared..];
+            block.data.extend_from_slice(&(shared as u32).to_le_bytes());
+            block.data.extend_from_slice(&(suffix.len() as u32).to_le_bytes());
+            block.data.extend_from_slice(suffix);
+        }
+        block
+    }
+
+    // the entries of the block that starts with `first`, in key order
+    fn decode(&self, first: &ByteStr) -> Vec<(ByteString, u64)> {
+        let mut entries = Vec::with_capacity(self.positions.len());
+        entries.push((first.to_vec(), self.positions[0]));
+        let mut current = first.to_vec();
+        let mut start = 0;
+        for &position in &self.positions[1..] {
+            let (shared, next, suffix) = self.entry(start);
+            current.truncate(shared);
+            current.extend_from_slice(suffix);
+            entries.push((current.clone(), position));
+            start = next;
+        }
+        entries
+    }
+
+    // the entry at `start` of `data`: its shared length, where the next one
+    // starts, and its suffix
+    fn entry(&self, start: usize) -> (usize, usize, &ByteStr) {
+        let word = |at: usize| {
+            u32::from_le_bytes(self.data[at..at + 4].try_into().unwrap_or_default()) as usize
+        };
+        let (shared, len) = (word(start), word(start + 4));
+        let suffix = &self.data[start + 8..start + 8 + len];
+        (shared, start + 8 + len, suffix)
+    }
+}
+
+impl CompressedIndex {
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.blocks.clear();
+        self.len = 0;
+    }
+
+    // the block `key` belongs in: the last one starting at or before it
+    fn block_of(&self, key: &ByteStr) -> Option<(&ByteString, &Block)> {
+        let bounds = (Bound::Unbounded, Bound::Included(key));
+        self.blocks.range::<ByteStr, _>(bounds).next_back()
+    }
+
+    pub(crate) fn get(&self, key: &ByteStr) -> Option<&u64> {
+        let (first, block) = self.block_of(key)?;
+        if first.as_slice() == key {
+            return block.positions.first();
+        }
+        let mut current = first.clone();
+        let mut start = 0;
+        for position in &block.positions[1..] {
+            let (shared, next, suffix) = block.entry(start);
+            current.truncate(shared);
+            current.extend_from_slice(suffix);
+            match current.as_slice().cmp(key) {
+                Ordering::Less => start = next,
+                Ordering::Equal => return Some(position),
+                Ordering::Greater => return None,
+            }
+        }
+        None
+    }
+
+    pub(crate) fn insert(&mut self, key: ByteString, position: u64) -> Option<u64> {
+        // a key before every block opens the first one
+        let first = self.block_of(&key).or_else(|| self.blocks.iter().next());
+        let mut entries = match first.map(|(first, _)| first.clone()) {
+            Some(first) => self.take_block(&first),
+            None => Vec::new(),
+        };
+        let old = match entries.binary_search_by(|(k, _)| k.as_slice().cmp(&key)) {
+            Ok(i) => Some(std::mem::replace(&mut entries[i].1, position)),
+            Err(i) => {
+                entries.insert(i, (key, position));
+                self.len += 1;
+                None
+            }
+        };
+        self.put_block(entries);
+        old
+    }
+
+    pub(crate) fn remove(&mut self, key: &ByteStr) -> Option<u64> {
+        let first = self.block_of(key)?.0.clone();
+        let mut entries = self.take_block(&first);
+        let old = match entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+            Ok(i) => {
+                self.len -= 1;
+                Some(entries.remove(i).1)
+            }
+            Err(_) => None,
+        };
+        self.put_block(entries);
+        old
+    }
+
+    fn take_block(&mut self, first: &ByteStr) -> Vec<(ByteString, u64)> {
+        match self.blocks.remove(first) {
+            Some(block) => block.decode(first),
+            None => Vec::new(),
+        }
+    }
+
+    // files `entries` as one block, or as two halves if they don't fit
+    fn put_block(&mut self, entries: Vec<(ByteString, u64)>) {
+        let chunk = if entries.len() > 2 * BLOCK {
+            entries.len().div_ceil(2)
+        } else {
+            entries.len().max(1)
+        };
+        for entries in entries.chunks(chunk) {
+            self.blocks.insert(entries[0].0.clone(), Block::encode(entries));
+        }
+    }
+
+    // builds the index from entries in any order, replacing what was there;
+    // a key given twice keeps its last position
+    pub(crate) fn rebuild(&mut self, mut entries: Vec<(ByteString, u64)>) {
+        // stable, so the later of two equal keys stays behind the earlier
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries.reverse();
+        entries.dedup_by(|a, b| a.0 == b.0);
+        entries.reverse();
+        self.clear();
+        self.len = entries.len();
+        for entries in entries.chunks(BLOCK) {
+            self.blocks.insert(entries[0].0.clone(), Block::encode(entries));
+        }
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        for block in self.blocks.values_mut() {
+            block.data.shrink_to_fit();
+            block.positions.shrink_to_fit();
+        }
+    }
+
+    pub(crate) fn iter(&self) -> Iter<'_> {
+        Iter {
+            blocks: self.blocks.iter(),
+            block: None,
+            current: ByteString::new(),
+            i: 0,
+            start: 0,
+        }
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = u64> + '_ {
+        self.blocks.values().flat_map(|block| block.positions.iter().copied())
+    }
+
+    // The heap buffers of every block and its first key, plus a slot in
+    // the block map for each, counted as if the map's nodes were half full.
+    pub(crate) fn memory_bytes(&self) -> usize {
+        let slot = std::mem::size_of::<(ByteString, Block)>();
+        let heap: usize = self
+            .blocks
+            .iter()
+            .map(|(first, block)| {
+                first.capacity()
+                    + block.data.capacity()
+                    + block.positions.capacity() * std::mem::size_of::<u64>()
+            })
+            .sum();
+        self.blocks.len() * slot * 2 + heap
+    }
+}
+
+// decodes the keys block by block, in key order
+pub(crate) struct Iter<'a> {
+    blocks: std::collections::btree_map::Iter<'a, ByteString, Block>,
+    block: Option<&'a Block>,
+    current: ByteString,
+    // the entry of the block that comes next, and where in `data` it starts
+    i: usize,
+    start: usize,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = (ByteString, u64);
+
+    fn next(&mut self) -> Option<(ByteString, u64)> {
+        if let Some(block) = self.block {
+            if self.i < block.positions.len() {
+                let (shared, next, suffix) = block.entry(self.start);
+                self.current.truncate(shared);
+                self.current.extend_from_slice(suffix);
+                self.start = next;
+                self.i += 1;
+                return Some((self.current.clone(), block.positions[self.i - 1]));
+            }
+        }
+        // every block has at least its first key
+        let (first, block) = self.blocks.next()?;
+        self.block = Some(block);
+        self.current.clone_from(first);
+        self.i = 1;
+        self.start = 0;
+        Some((first.clone(), block.positions[0]))
+    }
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// Keeps the index with front-coded keys instead of in a `HashMap`: in
+    /// key order, cut into blocks of a few dozen keys, and each key stored
+    /// as the length it shares with the key before it plus the rest. Keys
+    /// with long common prefixes take a fraction of the memory, see
+    /// `index_memory_bytes`, but every lookup searches for its block and
+    /// decodes up to a block's worth of keys, and every write re-encodes
+    /// the block it lands in. The keys already indexed are moved over;
+    /// `false` goes back to the `HashMap`.
+    pub fn set_compressed_index(&mut self, enabled: bool) {
+        self.index.set_compressed(enabled);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_db;
+    use std::io::Cursor;
+
+    fn prefixed(i: u32) -> String {
+        format!("/users/eu-west/accounts/settings/{:06}", i)
+    }
+
+    #[test]
+    fn prefixed_keys_take_less_memory() {
+        let mut plain = ActionKV::with_storage(Cursor::new(Vec::new()));
+        let mut compressed = ActionKV::with_storage(Cursor::new(Vec::new()));
+        compressed.set_compressed_index(true);
+        for i in 0..500u32 {
+            plain.insert(prefixed(i), b"v").unwrap();
+            compressed.insert(prefixed(i), b"v").unwrap();
+        }
+        assert_eq!(compressed.index, plain.index);
+        assert!(compressed.index_memory_bytes() * 2 < plain.index_memory_bytes());
+
+        assert_eq!(compressed.get(prefixed(7)).unwrap(), Some(b"v".to_vec()));
+        assert_eq!(compressed.get(prefixed(500)).unwrap(), None);
+        assert_eq!(compressed.get(b"/users").unwrap(), None);
+        assert_eq!(compressed.get(b"~").unwrap(), None);
+    }
+
+    #[test]
+    fn compressed_stores_follow_writes_and_reloads() {
+        let path = temp_db("compressed");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_compressed_index(true);
+        // in an order that splits blocks and changes their first keys
+        for i in (0..200u32).rev().step_by(3).chain(0..200) {
+            store.insert(prefixed(i), &i.to_le_bytes()).unwrap();
+        }
+        for i in (0..200u32).filter(|i| i % 7 == 0) {
+            store.delete(prefixed(i)).unwrap();
+        }
+        store.insert(prefixed(14), b"back").unwrap();
+        assert_eq!(store.index.len(), 200 - 28);
+        assert_eq!(store.get(prefixed(7)).unwrap(), None);
+        assert_eq!(store.get(prefixed(14)).unwrap(), Some(b"back".to_vec()));
+        assert_eq!(store.get(prefixed(199)).unwrap(), Some(199u32.to_le_bytes().to_vec()));
+
+        let mut plain = ActionKV::open(&path).unwrap();
+        plain.load().unwrap();
+        let mut reopened = ActionKV::open(&path).unwrap();
+        reopened.set_compressed_index(true);
+        reopened.load().unwrap();
+        assert_eq!(reopened.index, plain.index);
+        assert_eq!(store.index, plain.index);
+        let mut expected: Vec<ByteString> = plain.index.keys().map(Cow::into_owned).collect();
+        expected.sort();
+        assert_eq!(reopened.keys_matching("*").unwrap(), expected);
+
+        // compaction keeps the index the way it was
+        reopened.compact().unwrap();
+        assert!(reopened.index.is_compressed());
+        plain.reopen().unwrap();
+        assert_eq!(reopened.index, plain.index);
+
+        // and back to the `HashMap`
+        reopened.set_compressed_index(false);
+        assert_eq!(reopened.index, plain.index);
+        assert_eq!(reopened.get(prefixed(14)).unwrap(), Some(b"back".to_vec()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn blocks_agree_with_a_btreemap() {
+        let mut index = CompressedIndex::default();
+        let mut model = BTreeMap::new();
+        let mut seed = 0x5eedu64;
+        for position in 0..3000u64 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let key = format!("k{:03}", seed % 500).into_bytes();
+            if seed.is_multiple_of(3) {
+                assert_eq!(index.remove(&key), model.remove(&key));
+            } else {
+                assert_eq!(index.insert(key.clone(), position), model.insert(key, position));
+            }
+        }
+        assert_eq!(index.len(), model.len());
+        let entries: Vec<(ByteString, u64)> = index.iter().collect();
+        let expected: Vec<(ByteString, u64)> = model.clone().into_iter().collect();
+        assert_eq!(entries, expected);
+        assert!(index.values().eq(model.values().copied()));
+        for i in 0..500 {
+            let key = format!("k{:03}", i).into_bytes();
+            assert_eq!(index.get(&key), model.get(&key));
+        }
+
+        let mut rebuilt = CompressedIndex::default();
+        rebuilt.rebuild(expected.iter().rev().cloned().collect());
+        assert!(rebuilt.iter().eq(index.iter()));
+    }
+}
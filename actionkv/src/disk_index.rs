@@ -0,0 +1,258 @@
+use super::*;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// entries between two keys held in memory by a `DiskIndex`
+const BLOCK: usize = 16;
+
+// rough memory taken by one buffered entry besides its key
+const ENTRY_OVERHEAD: usize = std::mem::size_of::<(ByteString, Option<u64>)>();
+
+/// An index kept in a file of its own, for data files whose index wouldn't
+/// fit in memory, see `build_disk_index`. The file holds every live key with
+/// its record offset, sorted by key; only every 16th key is held in memory,
+/// so each lookup reads up to a block of entries from disk.
+#[derive(Debug)]
+pub struct DiskIndex {
+    f: BufReader<File>,
+    // first key of every block, and where the block starts in the file
+    blocks: Vec<(ByteString, u64)>,
+    len: usize,
+}
+
+impl DiskIndex {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The record offset of `key`, as `index.get` would give it.
+    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<u64>> {
+        let block = self.blocks.partition_point(|(first, _)| first.as_slice() <= key);
+        let start = match block.checked_sub(1) {
+            None => return Ok(None),
+            Some(block) => self.blocks[block].1,
+        };
+        self.f.seek(SeekFrom::Start(start))?;
+        for _ in 0..BLOCK {
+            let (found, position) = match read_entry(&mut self.f)? {
+                None => break,
+                Some((found, position)) => (found, position),
+            };
+            match found.as_slice().cmp(key) {
+                Ordering::Less => {}
+                Ordering::Equal => return Ok(position),
+                Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+}
+
+// [key_len u32][key][position u64, or u64::MAX for a tombstone]
+fn write_entry<W: Write>(w: &mut W, key: &ByteStr, position: Option<u64>) -> io::Result<()> {
+    w.write_u32::<LittleEndian>(key.len() as u32)?;
+    w.write_all(key)?;
+    w.write_u64::<LittleEndian>(position.unwrap_or(u64::MAX))
+}
+
+fn read_entry<R: Read>(r: &mut R) -> io::Result<Option<(ByteString, Option<u64>)>> {
+    let key_len = match r.read_u32::<LittleEndian>() {
+        Ok(len) => len,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut key = vec![0; key_len as usize];
+    r.read_exact(&mut key)?;
+    let position = r.read_u64::<LittleEndian>()?;
+    Ok(Some((key, Some(position).filter(|&p| p != u64::MAX))))
+}
+
+// Sorts a run by key, keeping only the last write to each key, and writes
+// it to `path`.
+fn spill(run: &mut Vec<(ByteString, Option<u64>)>, path: &Path) -> io::Result<()> {
+    // stable, so writes to the same key stay in file order
+    run.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut w = BufWriter::new(File::create(path)?);
+    for (i, (key, position)) in run.iter().enumerate() {
+        let superseded = run.get(i + 1).is_some_and(|(next, _)| next == key);
+        if !superseded {
+            write_entry(&mut w, key, *position)?;
+        }
+    }
+    run.clear();
+    w.flush()
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// Indexes the data file into a `DiskIndex` at `dest` within about
+    /// `memory_budget_bytes` of memory, for files too big for `load`. Keys
+    /// are collected until the budget is used up, then sorted and spilled to
+    /// a temporary run file next to `dest`; the runs are merged at the end,
+    /// later writes beating earlier ones. `self.index` is left alone.
+    pub fn build_disk_index(
+        &mut self,
+        dest: &Path,
+        memory_budget_bytes: usize,
+    ) -> io::Result<DiskIndex> {
+        self.check_file()?;
+        self.flush()?;
+        let start = self.detect_crc()?;
+        let crc = self.crc;
+
+        let mut runs = Vec::new();
+        let mut run = Vec::new();
+        let mut run_bytes = 0;
+        let mut batch: Option<OpenBatch> = None;
+        {
+            let mut f = BufReader::new(&mut self.f);
+            f.seek(SeekFrom::Start(start))?;
+            loop {
+                let position = f.stream_position()?;
+                let kv = match process_record(&mut f, crc) {
+                    Ok(kv) => kv,
+                    // a torn record, as in `load`
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(err) => return Err(err),
+                };
+
+                let mut records = Vec::new();
+                if let Some(open) = &mut batch {
+                    if position < open.end {
+                        open.records.push((kv, position));
+                        continue;
+                    }
+                }
+                let marker = marker_of(kv.checksum, &kv.key, &kv.value);
+                if let Some(open) = batch.take() {
+                    if position == open.end && marker == Some(Marker::Commit) {
+                        records = open.records;
+                    }
+                }
+                match marker {
+                    Some(Marker::Begin(len)) => batch = Some(OpenBatch::new(position, len)),
+                    Some(Marker::Commit) => {}
+                    None => records.push((kv, position)),
+                }
+
+                for (kv, position) in records {
+                    if kv.key.is_empty() {
+                        continue; // filler
+                    }
+                    let position = Some(position).filter(|_| !kv.value.is_empty());
+                    run_bytes += kv.key.len() + ENTRY_OVERHEAD;
+                    run.push((kv.key, position));
+                }
+                if run_bytes >= memory_budget_bytes {
+                    let path = dest.with_extension(format!("run{}", runs.len()));
+                    spill(&mut run, &path)?;
+                    runs.push(path);
+                    run_bytes = 0;
+                }
+            }
+        }
+        if !run.is_empty() || runs.is_empty() {
+            let path = dest.with_extension(format!("run{}", runs.len()));
+            spill(&mut run, &path)?;
+            runs.push(path);
+        }
+        debug!("merging {} runs into {}", runs.len(), dest.display());
+
+        let merged = merge_runs(&runs, dest);
+        for path in &runs {
+            let _ = fs::remove_file(path);
+        }
+        let (blocks, len) = merged?;
+        Ok(DiskIndex {
+            f: BufReader::new(File::open(dest)?),
+            blocks,
+            len,
+        })
+    }
+}
+
+// Merges the sorted `runs` into `dest`, dropping tombstones. Of several
+// entries for a key, the one from the latest run wins.
+fn merge_runs(runs: &[PathBuf], dest: &Path) -> io::Result<(Vec<(ByteString, u64)>, usize)> {
+    let mut readers = Vec::with_capacity(runs.len());
+    // the next entry of every run, smallest key and then latest run first
+    let mut heads = BinaryHeap::new();
+    for (i, path) in runs.iter().enumerate() {
+        let mut r = BufReader::new(File::open(path)?);
+        if let Some((key, position)) = read_entry(&mut r)? {
+            heads.push((Reverse(key), i, position));
+        }
+        readers.push(r);
+    }
+
+    let mut w = BufWriter::new(File::create(dest)?);
+    let (mut blocks, mut len, mut offset) = (Vec::new(), 0, 0);
+    while let Some((Reverse(key), i, position)) = heads.pop() {
+        // the same key from earlier runs
+        let mut advance = vec![i];
+        while heads.peek().is_some_and(|(Reverse(next), _, _)| *next == key) {
+            if let Some((_, older, _)) = heads.pop() {
+                advance.push(older);
+            }
+        }
+        for i in advance {
+            if let Some((key, position)) = read_entry(&mut readers[i])? {
+                heads.push((Reverse(key), i, position));
+            }
+        }
+
+        if let Some(position) = position {
+            if len % BLOCK == 0 {
+                blocks.push((key.clone(), offset));
+            }
+            write_entry(&mut w, &key, Some(position))?;
+            offset += 4 + key.len() as u64 + 8;
+            len += 1;
+        }
+    }
+    w.flush()?;
+    Ok((blocks, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_db;
+
+    #[test]
+    fn disk_index_matches_load_within_a_small_budget() {
+        let path = temp_db("disk-index");
+        let dest = temp_db("disk-index-out");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_flush_every_n(100);
+        for round in 0..3u64 {
+            for i in 0..500u64 {
+                if (i + round) % 5 == 0 {
+                    store.delete(i.to_be_bytes()).unwrap();
+                } else {
+                    store.insert_u64(i, i * round).unwrap();
+                }
+            }
+        }
+        store.commit_batch(&[(b"batched".to_vec(), b"v".to_vec())]).unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        // room for a few dozen keys at a time, a fraction of the 501 live
+        let mut disk = store.build_disk_index(&dest, 2048).unwrap();
+        assert_eq!(disk.len(), store.index.len());
+        for (key, position) in &store.index {
+            assert_eq!(disk.get(&key).unwrap(), Some(position));
+        }
+        for key in &[&b""[..], b"missing", &0u64.to_be_bytes(), &600u64.to_be_bytes()] {
+            assert_eq!(disk.get(key).unwrap(), store.index.get(key).cloned());
+        }
+        assert!(!dest.with_extension("run0").exists());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&dest).unwrap();
+    }
+}
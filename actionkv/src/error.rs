@@ -0,0 +1,46 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Failures that deserve a variant of their own, for callers that match on
+/// them. Everything else stays an `io::Error`, as returned by most methods.
+#[derive(Debug)]
+pub enum ActionKVError {
+    /// The key isn't in the index, see `ActionKV::get_or_error`.
+    KeyNotFound,
+    Io(io::Error),
+}
+
+impl fmt::Display for ActionKVError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ActionKVError::KeyNotFound => f.write_str("key not found"),
+            ActionKVError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for ActionKVError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ActionKVError::KeyNotFound => None,
+            ActionKVError::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for ActionKVError {
+    fn from(err: io::Error) -> Self {
+        ActionKVError::Io(err)
+    }
+}
+
+// so `?` still works in functions returning `io::Result`
+impl From<ActionKVError> for io::Error {
+    fn from(err: ActionKVError) -> Self {
+        match err {
+            ActionKVError::KeyNotFound => io::Error::new(io::ErrorKind::NotFound, err),
+            ActionKVError::Io(err) => err,
+        }
+    }
+}
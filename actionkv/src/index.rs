@@ -0,0 +1,269 @@
+use super::*;
+use crate::compressed::{self, CompressedIndex};
+use std::collections::hash_map;
+use std::iter::FromIterator;
+use std::ops;
+use std::sync::Arc;
+
+/// The record offset of every live key, kept in memory. A `HashMap` by
+/// default; `ActionKV::set_compressed_index` switches to front-coded keys,
+/// which iterate in key order and can't lend out their keys, so keys come
+/// back as `Cow`s. Clones share the keys until one of them changes, which
+/// copies them for that one.
+#[derive(Clone, Debug)]
+pub struct Index {
+    keys: Arc<Keys>,
+}
+
+#[derive(Clone, Debug)]
+enum Keys {
+    Hashed(HashMap<ByteString, u64, IndexHasher>),
+    // the hasher is kept for the `HashMap` this may turn back into
+    Compressed(CompressedIndex, IndexHasher),
+}
+
+impl Default for Index {
+    fn default() -> Index {
+        Index::with_capacity_and_hasher(0, IndexHasher::default())
+    }
+}
+
+impl Index {
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: IndexHasher) -> Index {
+        Index {
+            keys: Arc::new(Keys::Hashed(HashMap::with_capacity_and_hasher(capacity, hasher))),
+        }
+    }
+
+    /// An empty index kept the way this one is, with the same hasher.
+    pub fn empty_like(&self, capacity: usize) -> Index {
+        let mut index = Index::with_capacity_and_hasher(capacity, self.hasher().clone());
+        index.set_compressed(self.is_compressed());
+        index
+    }
+
+    pub fn hasher(&self) -> &IndexHasher {
+        match &*self.keys {
+            Keys::Hashed(map) => map.hasher(),
+            Keys::Compressed(_, hasher) => hasher,
+        }
+    }
+
+    // rehashes every key with `hasher`
+    pub(crate) fn set_hasher(&mut self, hasher: IndexHasher) {
+        match Arc::make_mut(&mut self.keys) {
+            Keys::Hashed(map) => {
+                let mut rehashed = HashMap::with_capacity_and_hasher(map.capacity(), hasher);
+                rehashed.extend(map.drain());
+                *map = rehashed;
+            }
+            Keys::Compressed(_, old) => *old = hasher,
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        matches!(*self.keys, Keys::Compressed(..))
+    }
+
+    pub(crate) fn set_compressed(&mut self, enabled: bool) {
+        if enabled == self.is_compressed() {
+            return;
+        }
+        let hasher = self.hasher().clone();
+        let entries: Vec<(ByteString, u64)> =
+            self.iter().map(|(k, v)| (k.into_owned(), v)).collect();
+        self.keys = Arc::new(if enabled {
+            let mut compressed = CompressedIndex::default();
+            compressed.rebuild(entries);
+            Keys::Compressed(compressed, hasher)
+        } else {
+            let mut map = HashMap::with_capacity_and_hasher(entries.len(), hasher);
+            map.extend(entries);
+            Keys::Hashed(map)
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        match &*self.keys {
+            Keys::Hashed(map) => map.len(),
+            Keys::Compressed(compressed, _) => compressed.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: &ByteStr) -> Option<&u64> {
+        match &*self.keys {
+            Keys::Hashed(map) => map.get(key),
+            Keys::Compressed(compressed, _) => compressed.get(key),
+        }
+    }
+
+    pub fn contains_key(&self, key: &ByteStr) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Files `key` under `position` and returns the position it had.
+    pub fn insert(&mut self, key: ByteString, position: u64) -> Option<u64> {
+        match Arc::make_mut(&mut self.keys) {
+            Keys::Hashed(map) => map.insert(key, position),
+            Keys::Compressed(compressed, _) => compressed.insert(key, position),
+        }
+    }
+
+    pub fn remove(&mut self, key: &ByteStr) -> Option<u64> {
+        match Arc::make_mut(&mut self.keys) {
+            Keys::Hashed(map) => map.remove(key),
+            Keys::Compressed(compressed, _) => compressed.remove(key),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        // no point copying keys that are about to go
+        if Arc::get_mut(&mut self.keys).is_none() {
+            *self = self.empty_like(0);
+            return;
+        }
+        match Arc::make_mut(&mut self.keys) {
+            Keys::Hashed(map) => map.clear(),
+            Keys::Compressed(compressed, _) => compressed.clear(),
+        }
+    }
+
+    /// Keys the index has room for without allocating; front-coded keys
+    /// never have spare room.
+    pub fn capacity(&self) -> usize {
+        match &*self.keys {
+            Keys::Hashed(map) => map.capacity(),
+            Keys::Compressed(compressed, _) => compressed.len(),
+        }
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        if let Keys::Hashed(map) = Arc::make_mut(&mut self.keys) {
+            map.reserve(additional);
+        }
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        match Arc::make_mut(&mut self.keys) {
+            Keys::Hashed(map) => map.shrink_to_fit(),
+            Keys::Compressed(compressed, _) => compressed.shrink_to_fit(),
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter(match &*self.keys {
+            Keys::Hashed(map) => Entries::Hashed(map.iter()),
+            Keys::Compressed(compressed, _) => Entries::Compressed(compressed.iter()),
+        })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Cow<'_, ByteStr>> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> Box<dyn Iterator<Item = u64> + '_> {
+        match &*self.keys {
+            Keys::Hashed(map) => Box::new(map.values().copied()),
+            Keys::Compressed(compressed, _) => Box::new(compressed.values()),
+        }
+    }
+
+    // whether the two are still clones sharing their keys
+    #[cfg(test)]
+    pub(crate) fn shares_keys_with(&self, other: &Index) -> bool {
+        Arc::ptr_eq(&self.keys, &other.keys)
+    }
+
+    /// Rough number of bytes held, see `ActionKV::index_memory_bytes`.
+    pub fn memory_bytes(&self) -> usize {
+        match &*self.keys {
+            Keys::Hashed(map) => {
+                let slot = std::mem::size_of::<(ByteString, u64)>() + 1;
+                let keys: usize = map.keys().map(|key| key.capacity()).sum();
+                map.capacity() * slot + keys
+            }
+            Keys::Compressed(compressed, _) => compressed.memory_bytes(),
+        }
+    }
+}
+
+/// The entries of an `Index`, in no particular order for a `HashMap` and
+/// in key order for front-coded keys.
+pub struct Iter<'a>(Entries<'a>);
+
+enum Entries<'a> {
+    Hashed(hash_map::Iter<'a, ByteString, u64>),
+    Compressed(compressed::Iter<'a>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (Cow<'a, ByteStr>, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.0 {
+            Entries::Hashed(entries) => {
+                let (key, &position) = entries.next()?;
+                Some((Cow::Borrowed(key.as_slice()), position))
+            }
+            Entries::Compressed(entries) => {
+                let (key, position) = entries.next()?;
+                Some((Cow::Owned(key), position))
+            }
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Index {
+    type Item = (Cow<'a, ByteStr>, u64);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+// same keys at the same positions, however either side keeps them
+impl PartialEq for Index {
+    fn eq(&self, other: &Index) -> bool {
+        self.len() == other.len()
+            && self.iter().all(|(key, position)| other.get(&key) == Some(&position))
+    }
+}
+
+impl Eq for Index {}
+
+impl Extend<(ByteString, u64)> for Index {
+    fn extend<I: IntoIterator<Item = (ByteString, u64)>>(&mut self, entries: I) {
+        match Arc::make_mut(&mut self.keys) {
+            Keys::Hashed(map) => map.extend(entries),
+            Keys::Compressed(compressed, _) if compressed.len() == 0 => {
+                compressed.rebuild(entries.into_iter().collect())
+            }
+            Keys::Compressed(compressed, _) => {
+                for (key, position) in entries {
+                    compressed.insert(key, position);
+                }
+            }
+        }
+    }
+}
+
+impl FromIterator<(ByteString, u64)> for Index {
+    fn from_iter<I: IntoIterator<Item = (ByteString, u64)>>(entries: I) -> Index {
+        let mut index = Index::default();
+        index.extend(entries);
+        index
+    }
+}
+
+impl<K: AsRef<ByteStr>> ops::Index<K> for Index {
+    type Output = u64;
+
+    fn index(&self, key: K) -> &u64 {
+        self.get(key.as_ref()).expect("key not in the index")
+    }
+}
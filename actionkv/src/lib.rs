@@ -1,66 +1,2925 @@
 #[macro_use]
 extern crate serde_derive;
 
+#[macro_use]
+extern crate log;
+
 extern crate byteorder;
 extern crate crc;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use crc::crc32;
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
+use crc::Hasher32;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter, SeekFrom};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+mod batch;
+mod compaction;
+mod compressed;
+mod disk_index;
+mod error;
+mod index;
+mod merge;
+mod parallel;
+mod record;
+mod recover;
+mod repair;
+mod replication;
+mod sidecar;
+mod storage;
+mod throttle;
+
+pub use compaction::{CompactEstimate, Compaction};
+pub use disk_index::DiskIndex;
+pub use error::ActionKVError;
+pub use index::Index;
+pub use merge::{merge_databases, ConflictPolicy};
+pub use repair::RepairReport;
+pub use record::{
+    decode_record, decode_record_with, encode_record, encode_record_with, read_record,
+    read_record_with, CrcVariant,
+};
+pub use sidecar::IndexFormat;
+pub use storage::Storage;
+pub use throttle::ThrottleMode;
+use batch::{marker_of, Marker, OpenBatch};
+use record::{
+    decode_record_ref, encode_filler, parse_header, process_record, write_record, MAX_TAG_LEN,
+};
+use sidecar::IndexLog;
+use throttle::TokenBucket;
 
-type ByteString = Vec<u8>;
-type ByteStr = [u8];
+pub type ByteString = Vec<u8>;
+pub type ByteStr = [u8];
 
-#[derive(Debug, Serialize, Deserialize)] // #[derive(Debug)]
+// checksum, key length and value length, each a u32
+const HEADER_LEN: u64 = 12;
+
+// buffer size for `get_stream`
+const STREAM_CHUNK: usize = 8 << 10;
+
+// how often `load_with_progress` reports back
+const PROGRESS_INTERVAL: u64 = if cfg!(test) { 64 } else { 4 << 20 };
+
+#[derive(Serialize, Deserialize)] // #[derive(Debug)]
 pub struct KeyValuePair {
     pub key: ByteString,
     pub value: ByteString,
+    checksum: u32,
+    #[serde(default)]
+    crc: CrcVariant,
+    // `Debug` shows only the length of the value, see `set_redacted`
+    #[serde(skip)]
+    redacted: bool,
+}
+
+impl fmt::Debug for KeyValuePair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut out = f.debug_struct("KeyValuePair");
+        out.field("key", &self.key);
+        if self.redacted {
+            out.field("value", &format_args!("<{} bytes>", self.value.len()));
+        } else {
+            out.field("value", &self.value);
+        }
+        out.field("checksum", &self.checksum).finish()
+    }
+}
+
+impl KeyValuePair {
+    pub fn new(key: ByteString, value: ByteString) -> Self {
+        let crc = CrcVariant::Ieee;
+        let checksum = crc.checksum(&key, &value);
+        KeyValuePair {
+            key,
+            value,
+            checksum,
+            crc,
+            redacted: false,
+        }
+    }
+
+    /// Keeps the value out of the `Debug` output, which then only shows its
+    /// length, so pairs can be logged without leaking their contents. Pairs
+    /// read from a store with `set_redact_values` come redacted.
+    pub fn set_redacted(&mut self, redacted: bool) {
+        self.redacted = redacted;
+    }
+
+    /// The checksum stored alongside the record on disk.
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    /// Recomputes the checksum over key and value and compares it with the
+    /// stored one, e.g. for pairs that travelled over the network. Always
+    /// true for pairs from a file without checksums.
+    pub fn verify(&self) -> bool {
+        !self.crc.verifies() || self.crc.checksum(&self.key, &self.value) == self.checksum
+    }
+}
+
+// Adds what `load` finds at `position` to the index, noting the records it
+// makes obsolete in `dead`.
+fn index_record(
+    index: &mut Index,
+    free_list: &mut Option<BTreeMap<u64, u64>>,
+    kv: KeyValuePair,
+    position: u64,
+    dead: &mut Vec<u64>,
+) {
+    if kv.key.is_empty() {
+        // filler, already free space
+        if let Some(free) = free_list {
+            free.insert(position, HEADER_LEN + kv.value.len() as u64);
+        }
+    } else if kv.value.is_empty() {
+        // tombstone
+        if let Some(old) = index.remove(&kv.key) {
+            dead.push(old);
+        }
+        dead.push(position);
+    } else if let Some(old) = index.insert(kv.key, position) {
+        dead.push(old);
+    }
+}
+
+// Whether `text` matches the glob `pattern`. A mismatch after a `*` retries
+// with the star taking one more character, so this never backtracks further
+// than the last star.
+fn glob_matches(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // position of the last star in the pattern, and in the text when it
+    // was reached
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// bytes of filler needed before `position` to reach the next multiple of
+// `alignment`, either none or enough room for a filler header
+fn padding_for(position: u64, alignment: u64) -> u64 {
+    let mut padding = (alignment - position % alignment) % alignment;
+    while padding > 0 && padding < HEADER_LEN {
+        padding += alignment;
+    }
+    padding
+}
+
+fn open_file(path: &Path) -> io::Result<File> {
+    open_file_with(path, true)
+}
+
+fn open_file_with(path: &Path, create: bool) -> io::Result<File> {
+    // no .append(true): the free list needs to write into the middle of
+    // the file, so every write seeks to its position explicitly; see
+    // `open_append_only` for a store that keeps it
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(create)
+        .truncate(false)
+        .open(path)
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).append(true).create(true).open(path)
+}
+
+#[cfg(unix)]
+fn file_id(meta: &fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (meta.dev(), meta.ino())
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &fs::Metadata) -> (u64, u64) {
+    (0, 0)
+}
+
+impl<S: Storage> Drop for ActionKV<S> {
+    fn drop(&mut self) {
+        // like BufWriter, errors here go unreported; call `flush` to see them
+        if self.flush_on_drop {
+            let _ = self.flush();
+        }
+    }
+}
+
+/// Displays a key as text, replacing invalid UTF-8 with U+FFFD, while
+/// keeping the exact bytes around for lookups.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyDisplay<'a>(pub &'a ByteStr);
+
+impl<'a> KeyDisplay<'a> {
+    pub fn bytes(&self) -> &'a ByteStr {
+        self.0
+    }
+}
+
+impl fmt::Display for KeyDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(self.0))
+    }
+}
+
+/// Hashes index keys either with a per-process random seed (the `HashMap`
+/// default) or with fixed keys, which keeps the iteration order of the index
+/// stable from one run to the next.
+#[derive(Clone, Debug)]
+pub enum IndexHasher {
+    Random(RandomState),
+    Deterministic,
+}
+
+impl Default for IndexHasher {
+    fn default() -> Self {
+        IndexHasher::Random(RandomState::new())
+    }
+}
+
+impl BuildHasher for IndexHasher {
+    type Hasher = DefaultHasher;
+
+    fn build_hasher(&self) -> DefaultHasher {
+        match self {
+            IndexHasher::Random(state) => state.build_hasher(),
+            IndexHasher::Deterministic => DefaultHasher::new(),
+        }
+    }
+}
+
+/// Decides the key order of range scans and the other ordered operations.
+pub type KeyComparator = Arc<dyn Fn(&ByteStr, &ByteStr) -> Ordering + Send + Sync>;
+
+fn bytewise(a: &ByteStr, b: &ByteStr) -> Ordering {
+    a.cmp(b)
+}
+
+// the comparator of a store, which closures leave without a `Debug` of
+// their own
+#[derive(Clone)]
+struct Comparator(KeyComparator);
+
+impl fmt::Debug for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("KeyComparator")
+    }
 }
 
+/// An append-only key-value store over a single data file.
+///
+/// Threading: every operation takes `&mut self`, so a store is used by one
+/// thread at a time and needs no locking inside. It is `Send` and `Sync`
+/// whenever its storage is (a `File` is), so it can be moved into a
+/// dedicated writer thread, or shared behind a `Mutex`. Two stores opened on
+/// the same file don't coordinate with each other.
 #[derive(Debug)] // #[derive(Debug)]
-pub struct ActionKV {
-    f: File,
-    pub index: HashMap<ByteString, u64>,
+pub struct ActionKV<S: Storage = File> {
+    f: S,
+    // set when the storage is a file opened by path
+    path: Option<PathBuf>,
+    // identifies the file behind `f`, to notice it being removed or replaced
+    file_id: (u64, u64),
+    pub index: Index,
+    // offset -> length of every free slot; `None` keeps the file append-only
+    free_list: Option<BTreeMap<u64, u64>>,
+    // appended records not yet written to the file
+    pending: ByteString,
+    pending_count: usize,
+    flush_every_n: usize,
+    alignment: u64,
+    crc: CrcVariant,
+    redact_values: bool,
+    index_format: IndexFormat,
+    index_log: Option<IndexLog>,
+    throttle: Option<TokenBucket>,
+    compare: Comparator,
+    quarantine: bool,
+    flush_on_drop: bool,
+    overwrite_in_place: bool,
+    max_file_size: Option<u64>,
+    // opened with O_APPEND, where every write goes to the end of the file
+    append_only: bool,
+    // run when an append would go past `max_file_size`, see
+    // `set_compact_when_full`
+    compact_when_full: Option<fn(&mut Self) -> io::Result<()>>,
 }
 
 impl ActionKV {
+    /// Opens the data file at `path`, creating it if it doesn't exist. A
+    /// file that can't be written to, including one merely marked read-only,
+    /// fails here with `PermissionDenied` rather than on the first write.
     pub fn open(path: &Path) -> io::Result<Self> {
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(path)?;
-        let index = HashMap::new();
-        return Ok(ActionKV { f, index });
-    }
-}
-
-fn process_record<R: Read>(f: &mut R) -> io::Result<KeyValuePair> {
-    let saved_checksum = f.read_u32::<LittleEndian>()?;
-    let key_len = f.read_u32::<LittleEndian>()?;
-    let val_len = f.read_u32::<LittleEndian>()?;
-    let data_len = key_len + val_len;
-    let mut data = ByteString::with_capacity(data_len as usize);
+        ActionKV::open_with(path, true)
+    }
+
+    /// Like `open`, but fails with `NotFound` instead of creating a missing
+    /// file, so a mistyped path doesn't quietly start an empty database.
+    pub fn open_existing(path: &Path) -> io::Result<Self> {
+        ActionKV::open_with(path, false)
+    }
+
+    /// Like `open`, but with the file opened for appending, so the OS puts
+    /// every write at the end of the file whatever the seek position. Even a
+    /// stray write can't damage existing records, but nothing is written in
+    /// place either: deletes and updates under the free list or
+    /// `set_overwrite_in_place` fail with `Unsupported`.
+    pub fn open_append_only(path: &Path) -> io::Result<Self> {
+        let mut store = ActionKV::open(path)?;
+        store.f = open_append(path)?;
+        store.append_only = true;
+        Ok(store)
+    }
+
+    // opens `path` the way this store's file was opened
+    fn open_own_file(&self, path: &Path) -> io::Result<File> {
+        if self.append_only {
+            open_append(path)
+        } else {
+            open_file(path)
+        }
+    }
+
+    fn open_with(path: &Path, create: bool) -> io::Result<Self> {
+        let not_writable = |reason: &dyn fmt::Display| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is not writable: {}", path.display(), reason),
+            )
+        };
+        let f = open_file_with(path, create).map_err(|err| match err.kind() {
+            io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+                not_writable(&err)
+            }
+            _ => err,
+        })?;
+        // the OS lets privileged users write to read-only files regardless
+        if f.metadata()?.permissions().readonly() {
+            return Err(not_writable(&"the file is read-only"));
+        }
+        let mut store = ActionKV::with_storage(f);
+        store.file_id = file_id(&store.f.metadata()?);
+        store.path = Some(path.to_path_buf());
+        store.detect_crc()?;
+        Ok(store)
+    }
+
+    /// Like `open`, but sizes the index for `capacity` keys up front so
+    /// `load` doesn't rehash as it grows.
+    pub fn open_with_capacity(path: &Path, capacity: usize) -> io::Result<Self> {
+        let mut store = ActionKV::open(path)?;
+        store.index.reserve(capacity);
+        Ok(store)
+    }
+
+    /// Opens the path again, creating the file if it's gone, and reloads the
+    /// index. Buffered records are written to the old file first.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        self.flush()?;
+        let path = self.path()?.to_path_buf();
+        self.f = self.open_own_file(&path)?;
+        self.file_id = file_id(&self.f.metadata()?);
+        self.index.clear();
+        if self.free_list.is_some() {
+            self.set_free_list(true)?;
+        }
+        self.load()
+    }
+
+    /// A second store over the same file, with a file handle and so a seek
+    /// position of its own, for reading alongside this one. The two share
+    /// the index until either writes, which gives the writer a copy of its
+    /// own, so neither sees the other's later writes. Fails with
+    /// `InvalidInput` while records are buffered, since the new handle
+    /// couldn't read them: `flush` first.
+    pub fn clone_handle(&self) -> io::Result<ActionKV> {
+        if !self.pending.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "buffered records have to be flushed before `clone_handle`",
+            ));
+        }
+        let mut other = ActionKV::open_existing(self.path()?)?;
+        other.index = self.index.clone();
+        other.crc = self.crc;
+        other.compare = self.compare.clone();
+        other.redact_values = self.redact_values;
+        Ok(other)
+    }
+
+    /// Opens `path`, creating it if needed, and writes every entry of `map`
+    /// into it in one append, in key order. Keys already in the file are
+    /// overwritten; an empty value deletes its key, as in `insert`.
+    pub fn from_hashmap(
+        path: &Path,
+        map: &HashMap<ByteString, ByteString>,
+    ) -> io::Result<ActionKV> {
+        let mut store = ActionKV::open(path)?;
+        store.load()?;
+        let mut entries: Vec<_> = map.iter().collect();
+        entries.sort_unstable();
+
+        let flush_every_n = store.flush_every_n;
+        store.set_flush_every_n(usize::MAX);
+        let written = entries
+            .into_iter()
+            .try_for_each(|(key, value)| store.insert(key, value))
+            .and_then(|()| store.flush());
+        store.flush_every_n = flush_every_n;
+        written?;
+        Ok(store)
+    }
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// A store over any `Storage`, e.g. a `Cursor<Vec<u8>>` for a purely
+    /// in-memory database. Call `load` to index existing contents.
+    pub fn with_storage(f: S) -> Self {
+        ActionKV {
+            f,
+            path: None,
+            file_id: (0, 0),
+            index: Index::default(),
+            free_list: None,
+            pending: ByteString::new(),
+            pending_count: 0,
+            flush_every_n: 1,
+            alignment: 1,
+            crc: CrcVariant::default(),
+            redact_values: false,
+            index_format: IndexFormat::default(),
+            index_log: None,
+            throttle: None,
+            compare: Comparator(Arc::new(bytewise)),
+            quarantine: false,
+            flush_on_drop: true,
+            overwrite_in_place: false,
+            max_file_size: None,
+            append_only: false,
+            compact_when_full: None,
+        }
+    }
+
+    // the path this store was opened from, for operations that need one
+    fn path(&self) -> io::Result<&Path> {
+        self.path.as_deref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the store was not opened from a path",
+            )
+        })
+    }
+
+    /// Fails if the file at our path is no longer the one we have open:
+    /// `NotFound` once it has been removed, `Other` if it was replaced. Reads
+    /// and writes check this before touching the file. Unix only; elsewhere
+    /// just the removal is detected. Always passes for storage that wasn't
+    /// opened from a path.
+    pub fn check_file(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let meta = match fs::metadata(path) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} was removed while open", path.display()),
+                ))
+            }
+            Err(err) => return Err(err),
+        };
+        if file_id(&meta) != self.file_id {
+            return Err(io::Error::other(format!(
+                "{} was replaced while open",
+                path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Buffers appended records in memory and writes them out once `n` have
+    /// accumulated, trading durability for fewer writes. Records still in
+    /// the buffer are lost if the process dies; they are written on `flush`,
+    /// on any read and when the store is dropped (see `set_flush_on_drop`).
+    /// `1` (the default) writes every record straight through.
+    pub fn set_flush_every_n(&mut self, n: usize) {
+        self.flush_every_n = n.max(1);
+    }
+
+    /// Makes appends that would grow the file past `bytes` fail with an
+    /// `Other` error instead, leaving the file as it was. Records that fit
+    /// into a free slot still go in. `None` lifts the limit.
+    pub fn set_max_file_size(&mut self, bytes: Option<u64>) {
+        self.max_file_size = bytes;
+    }
+
+    // where the file would end with a record of `record_len` bytes appended
+    fn end_after_append(&mut self, record_len: u64) -> io::Result<u64> {
+        let mut position = self.f.seek(SeekFrom::End(0))? + self.pending.len() as u64;
+        if position == 0 {
+            let mut header = Vec::new();
+            self.crc.encode_header(&mut header);
+            position = header.len() as u64;
+        }
+        Ok(position + padding_for(position, self.alignment) + record_len)
+    }
+
+    /// Lets `insert` write a new value over the old one when both are the
+    /// same length, instead of appending, so those updates neither grow the
+    /// file nor leave dead records. The file stops being append-only: the old
+    /// value is gone for good, a crash mid-write leaves a damaged record,
+    /// and `start_compaction` is unavailable. Off by default.
+    pub fn set_overwrite_in_place(&mut self, enabled: bool) {
+        self.overwrite_in_place = enabled;
+    }
+
+    /// With `false`, dropping the store throws away the records still
+    /// buffered by `set_flush_every_n` instead of writing them, so they are
+    /// lost as if the process had died. `true` is the default.
+    pub fn set_flush_on_drop(&mut self, enabled: bool) {
+        self.flush_on_drop = enabled;
+    }
+
+    /// Closes the store without writing the buffered records, see
+    /// `set_flush_on_drop`.
+    pub fn cancel(mut self) {
+        self.flush_on_drop = false;
+    }
+
+    /// Starts every newly appended record on a multiple of `alignment` bytes,
+    /// e.g. 8 to keep headers from straddling word or page boundaries. The
+    /// gap before a record is filled with a filler record, which readers
+    /// skip, so aligned and unaligned files read the same. Fillers need a
+    /// header of their own, so a gap is never shorter than 12 bytes. `1`,
+    /// the default, packs records tightly; compaction and exports always do.
+    pub fn set_alignment(&mut self, alignment: u64) {
+        self.alignment = alignment.max(1);
+    }
+
+    /// The checksum used for the records of a new, empty file. An existing
+    /// file keeps the variant it was written with, which `open` and `load`
+    /// switch to.
+    pub fn set_crc_variant(&mut self, crc: CrcVariant) {
+        self.crc = crc;
+    }
+
+    pub fn crc_variant(&self) -> CrcVariant {
+        self.crc
+    }
+
+    /// Orders keys with `compare` instead of byte by byte. `compare` can be
+    /// a closure, e.g. over a separator or radix of its own. The order is
+    /// not stored anywhere, so a file has to be opened with the same
+    /// comparator every time for range scans to stay consistent.
+    pub fn set_key_comparator<F>(&mut self, compare: F)
+    where
+        F: Fn(&ByteStr, &ByteStr) -> Ordering + Send + Sync + 'static,
     {
-        f.by_ref().take(data_len as u64).read_to_end(&mut data)?;
+        self.compare = Comparator(Arc::new(compare));
+    }
+
+    /// Hand out pairs whose `Debug` output leaves the value out, see
+    /// `KeyValuePair::set_redacted`.
+    pub fn set_redact_values(&mut self, enabled: bool) {
+        self.redact_values = enabled;
+    }
+
+    // adopts the variant of a non-empty file, returning where its records start
+    fn detect_crc(&mut self) -> io::Result<u64> {
+        let mut start = Vec::new();
+        self.f.seek(SeekFrom::Start(0))?;
+        (&mut self.f).take(HEADER_LEN + MAX_TAG_LEN as u64).read_to_end(&mut start)?;
+        if start.is_empty() {
+            return Ok(0);
+        }
+        let (crc, len) = CrcVariant::decode_header(&start);
+        self.crc = crc;
+        Ok(len)
+    }
+
+    /// Writes out buffered records, see `set_flush_every_n`. They go out in a
+    /// single write; if it fails, the file is cut back to its previous length
+    /// so no torn record is left behind, and the records stay buffered.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let end = self.f.seek(SeekFrom::End(0))?;
+        if let Err(err) = self.f.write_all(&self.pending) {
+            // the write error is the one worth reporting
+            let _ = self.f.set_len(end);
+            return Err(err);
+        }
+        self.pending.clear();
+        self.pending_count = 0;
+        Ok(())
+    }
+
+    /// Switches the index to a fixed-key hasher so `index.keys()` comes back
+    /// in the same order for the same file on every run of a given build.
+    pub fn set_deterministic_hashing(&mut self, enabled: bool) {
+        let hasher = if enabled {
+            IndexHasher::Deterministic
+        } else {
+            IndexHasher::default()
+        };
+        self.index.set_hasher(hasher);
+    }
+
+    /// Reuse the space of deleted and overwritten records instead of always
+    /// appending. Call before `load`, which then reclaims the dead records it
+    /// finds by turning them into free slots. Enabling it on a loaded store
+    /// fails with `InvalidInput`: the dead records found by that load would
+    /// never be reclaimed, and a later delete that frees the live record
+    /// without a tombstone would bring them back on the next load.
+    ///
+    /// With the free list enabled, deletes no longer append a tombstone; the
+    /// deleted record itself is overwritten with a filler header.
+    pub fn set_free_list(&mut self, enabled: bool) -> io::Result<()> {
+        if enabled && !self.index.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the free list has to be enabled before `load`",
+            ));
+        }
+        self.free_list = if enabled { Some(BTreeMap::new()) } else { None };
+        Ok(())
     }
-    debug_assert_eq!(data.len(), data_len as usize);
 
-    let checksum = crc32::checksum_ieee(&data);
-    if checksum != saved_checksum {
-        panic!(
-            "data corruption encountered ({:08x} != {:08x}",
-            checksum, saved_checksum
-        );
+    /// Total bytes available for reuse, zero when the free list is disabled.
+    pub fn free_bytes(&self) -> u64 {
+        match &self.free_list {
+            Some(free) => free.values().sum(),
+            None => 0,
+        }
+    }
+
+    pub fn load(&mut self) -> io::Result<()> {
+        self.load_with_progress(|_, _| {})
+    }
+
+    /// `load`, reporting `(bytes_read, file_len)` to `cb` every few megabytes
+    /// and once more when the scan is complete.
+    pub fn load_with_progress<F: FnMut(u64, u64)>(&mut self, cb: F) -> io::Result<()> {
+        self.load_records(cb, false).map(|_| ())
+    }
+
+    // The scan behind `load`. With `recover`, records that fail their
+    // checksum are skipped instead of failing the load, and returned along
+    // with their offsets.
+    fn load_records<F: FnMut(u64, u64)>(
+        &mut self,
+        mut cb: F,
+        recover: bool,
+    ) -> io::Result<Vec<(u64, ByteString)>> {
+        self.check_file()?;
+        self.flush()?;
+        let start = self.detect_crc()?;
+        let file_len = self.f.seek(SeekFrom::End(0))?;
+        let crc = self.crc;
+        let mut reported = 0;
+        let mut dead = Vec::new();
+        let mut batch: Option<OpenBatch> = None;
+        let mut corrupt = Vec::new();
+        {
+            let mut f = BufReader::new(&mut self.f);
+            f.seek(SeekFrom::Start(start))?;
+            loop {
+                let position = f.stream_position()?;
+                if position - reported >= PROGRESS_INTERVAL && position < file_len {
+                    debug!("indexed {} of {} bytes", position, file_len);
+                    cb(position, file_len);
+                    reported = position;
+                }
+                let kv = match read_record_with(crc, &mut f) {
+                    Ok(Some(kv)) => kv,
+                    Ok(None) => break,
+                    Err(err) => match err.kind() {
+                        io::ErrorKind::UnexpectedEof => {
+                            warn!(
+                                "ignoring a torn record of {} bytes at offset {}",
+                                file_len - position,
+                                position
+                            );
+                            break;
+                        }
+                        io::ErrorKind::InvalidData if recover => {
+                            warn!("skipping a corrupt record at offset {}", position);
+                            f.seek(SeekFrom::Start(position))?;
+                            let mut header = [0; HEADER_LEN as usize];
+                            f.read_exact(&mut header)?;
+                            let (_, key_len, val_len) = parse_header(&header);
+                            let mut raw = header.to_vec();
+                            let data_len = key_len as u64 + val_len as u64;
+                            (&mut f).take(data_len).read_to_end(&mut raw)?;
+                            corrupt.push((position, raw));
+                            continue;
+                        }
+                        _ => return Err(err),
+                    },
+                };
+
+                if let Some(open) = &mut batch {
+                    if position < open.end {
+                        open.records.push((kv, position));
+                        continue;
+                    }
+                }
+                let marker = marker_of(kv.checksum, &kv.key, &kv.value);
+                if let Some(open) = batch.take() {
+                    if position == open.end && marker == Some(Marker::Commit) {
+                        for (kv, position) in open.records {
+                            let free_list = &mut self.free_list;
+                            index_record(&mut self.index, free_list, kv, position, &mut dead);
+                        }
+                        continue;
+                    }
+                    warn!("ignoring the uncommitted batch at offset {}", open.start);
+                }
+                match marker {
+                    Some(Marker::Begin(len)) => batch = Some(OpenBatch::new(position, len)),
+                    Some(Marker::Commit) => {}
+                    None => {
+                        let free_list = &mut self.free_list;
+                        index_record(&mut self.index, free_list, kv, position, &mut dead);
+                    }
+                }
+            }
+        }
+
+        // only a tagged filler opens a batch, and every record since was
+        // inside it, so the file ends in the middle of a `commit_batch`
+        if let Some(open) = batch {
+            warn!("dropping the uncommitted batch at offset {}", open.start);
+            self.f.set_len(open.start)?;
+        }
+
+        debug!("indexed {} keys from {} bytes", self.index.len(), file_len);
+        cb(file_len, file_len);
+
+        if self.free_list.is_some() {
+            for position in dead {
+                self.free_record(position)?;
+            }
+        }
+        Ok(corrupt)
+    }
+
+    pub fn seek_to_end(&mut self) -> io::Result<u64> {
+        self.flush()?;
+        self.f.seek(SeekFrom::End(0))
+    }
+
+    /// Whether `key` currently has a value.
+    pub fn contains_key<K: AsRef<ByteStr>>(&self, key: K) -> bool {
+        self.index.contains_key(key.as_ref())
+    }
+
+    pub fn get<K: AsRef<ByteStr>>(&mut self, key: K) -> io::Result<Option<ByteString>> {
+        let key = key.as_ref();
+        let position = match self.index.get(key) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+        let kv = self.get_at(position)?;
+        Ok(Some(kv.value))
+    }
+
+    /// Like `get`, but a missing key is an `ActionKVError::KeyNotFound`
+    /// rather than `None`.
+    pub fn get_or_error<K: AsRef<ByteStr>>(
+        &mut self,
+        key: K,
+    ) -> Result<ByteString, ActionKVError> {
+        self.get(key)?.ok_or(ActionKVError::KeyNotFound)
+    }
+
+    /// Reads the record the index points at for `key` and checks that it
+    /// really is a record of `key`, to catch an index that has drifted from
+    /// the file. An offset landing on another key, or on something that
+    /// doesn't decode as a record, gives `false`, as does a missing key.
+    pub fn verify_key<K: AsRef<ByteStr>>(&mut self, key: K) -> io::Result<bool> {
+        let key = key.as_ref();
+        let position = match self.index.get(key) {
+            None => return Ok(false),
+            Some(position) => *position,
+        };
+        match self.get_at(position) {
+            Ok(kv) => Ok(kv.key == key),
+            Err(err) => match err.kind() {
+                io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => Ok(false),
+                _ => Err(err),
+            },
+        }
+    }
+
+    /// Copies the value of `key` into `out` in small chunks, so huge values
+    /// never have to fit into memory, and returns its length. The checksum is
+    /// updated as the bytes pass through; a mismatch is reported as
+    /// `InvalidData` once the whole value has been written to `out`.
+    pub fn get_stream<W: Write>(&mut self, key: &ByteStr, out: &mut W) -> io::Result<Option<u64>> {
+        let position = match self.index.get(key) {
+            None => return Ok(None),
+            Some(position) => *position,
+        };
+        self.check_file()?;
+        self.flush()?;
+
+        let mut f = BufReader::new(&mut self.f);
+        f.seek(SeekFrom::Start(position))?;
+        let mut header = [0; HEADER_LEN as usize];
+        f.read_exact(&mut header)?;
+        let (saved_checksum, key_len, val_len) = parse_header(&header);
+        let (key_len, val_len) = (key_len as u64, val_len as u64);
+
+        // `None` for files without checksums
+        let mut digest = self.crc.digest();
+        let mut key = vec![0; key_len as usize];
+        f.read_exact(&mut key)?;
+        if let Some(digest) = &mut digest {
+            digest.write(&key);
+        }
+
+        let mut chunk = [0; STREAM_CHUNK];
+        let mut left = val_len;
+        while left > 0 {
+            let n = left.min(STREAM_CHUNK as u64) as usize;
+            f.read_exact(&mut chunk[..n])?;
+            if let Some(digest) = &mut digest {
+                digest.write(&chunk[..n]);
+            }
+            out.write_all(&chunk[..n])?;
+            left -= n as u64;
+        }
+
+        let checksum = digest.map_or(saved_checksum, |digest| digest.sum32());
+        if checksum != saved_checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "data corruption encountered ({:08x} != {:08x})",
+                    checksum, saved_checksum
+                ),
+            ));
+        }
+        Ok(Some(val_len))
+    }
+
+    /// Like `get`, but borrows the value straight out of storage that keeps
+    /// its contents in memory (see `Storage::as_bytes`). Other storage, such
+    /// as a `File`, returns an owned copy.
+    pub fn get_cow(&mut self, key: &ByteStr) -> io::Result<Option<Cow<'_, [u8]>>> {
+        let position = match self.index.get(key) {
+            None => return Ok(None),
+            Some(position) => *position as usize,
+        };
+        self.flush()?;
+        // asked twice: returning the borrow from a match would keep `self`
+        // borrowed in the fallback branch as well
+        if self.f.as_bytes().is_none() {
+            return Ok(self.get(key)?.map(Cow::Owned));
+        }
+
+        let bytes = self.f.as_bytes().unwrap_or_default();
+        let record = bytes.get(position..).ok_or(io::ErrorKind::UnexpectedEof)?;
+        let (_, value) = decode_record_ref(self.crc, record)?;
+        Ok(Some(Cow::Borrowed(value)))
+    }
+
+    /// Looks up several keys at once, returning their values in the order
+    /// of `keys`. The records are read in file order to keep the access
+    /// sequential, and like `get_cow` the values borrow from storage that is
+    /// already in memory.
+    pub fn get_many_cow<K: AsRef<ByteStr>>(
+        &mut self,
+        keys: &[K],
+    ) -> io::Result<Vec<Option<Cow<'_, [u8]>>>> {
+        let mut found: Vec<(u64, usize)> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| self.index.get(key.as_ref()).map(|&position| (position, i)))
+            .collect();
+        found.sort_unstable();
+        self.flush()?;
+
+        let mut values: Vec<Option<Cow<[u8]>>> = keys.iter().map(|_| None).collect();
+        if self.f.as_bytes().is_none() {
+            for (position, i) in found {
+                values[i] = Some(Cow::Owned(self.get_at(position)?.value));
+            }
+            return Ok(values);
+        }
+
+        let bytes = self.f.as_bytes().unwrap_or_default();
+        for (position, i) in found {
+            let record = bytes.get(position as usize..).ok_or(io::ErrorKind::UnexpectedEof)?;
+            let (_, value) = decode_record_ref(self.crc, record)?;
+            values[i] = Some(Cow::Borrowed(value));
+        }
+        Ok(values)
+    }
+
+    pub fn get_at(&mut self, position: u64) -> io::Result<KeyValuePair> {
+        self.check_file()?;
+        self.flush()?;
+        let mut f = BufReader::new(&mut self.f);
+        f.seek(SeekFrom::Start(position))?;
+        let mut kv = process_record(&mut f, self.crc)?;
+        kv.redacted = self.redact_values;
+        Ok(kv)
+    }
+
+    pub fn insert<K: AsRef<ByteStr>>(&mut self, key: K, value: &ByteStr) -> io::Result<()> {
+        let key = key.as_ref();
+        if key.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty keys are reserved for filler records",
+            ));
+        }
+        if value.is_empty() {
+            return self.delete(key);
+        }
+        if let Some(throttle) = &mut self.throttle {
+            throttle.take(HEADER_LEN + (key.len() + value.len()) as u64)?;
+        }
+
+        if self.overwrite_in_place && self.overwrite(key, value)? {
+            return Ok(());
+        }
+        let position = self.insert_but_ignore_index(key, value)?;
+        let old = self.index.insert(key.to_vec(), position);
+        self.log_index(key, Some(position))?;
+        if let Some(old) = old {
+            if self.free_list.is_some() {
+                self.free_record(old)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores `value` under its SHA-256 digest and returns the digest, which
+    /// `get_blob` takes to read it back. A value that is already stored is
+    /// not written again, so identical blobs share one record.
+    pub fn put_blob(&mut self, value: &ByteStr) -> io::Result<ByteString> {
+        let key = Sha256::digest(value).to_vec();
+        if !self.index.contains_key(&key) {
+            self.insert(&key, value)?;
+        }
+        Ok(key)
+    }
+
+    /// The value that `put_blob` returned `hash` for.
+    pub fn get_blob(&mut self, hash: &ByteStr) -> io::Result<Option<ByteString>> {
+        self.get(hash)
+    }
+
+    /// Writes the record and returns its offset, without touching the index.
+    pub fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<u64> {
+        self.check_file()?;
+        let record_len = HEADER_LEN + (key.len() + value.len()) as u64;
+        if let Some(position) = self.take_free_slot(record_len)? {
+            let mut buf = Vec::new();
+            encode_record_with(self.crc, key, value, &mut buf);
+            self.f.seek(SeekFrom::Start(position))?;
+            if let Err(err) = self.f.write_all(&buf) {
+                // hand the slot back, with a filler over whatever made it out
+                let _ = self.write_filler(position, record_len);
+                if let Some(free) = &mut self.free_list {
+                    free.insert(position, record_len);
+                }
+                return Err(err);
+            }
+            return Ok(position);
+        }
+
+        if let Some(limit) = self.max_file_size {
+            if self.end_after_append(record_len)? > limit {
+                if let Some(compact) = self.compact_when_full {
+                    compact(self)?;
+                }
+                if self.end_after_append(record_len)? > limit {
+                    return Err(io::Error::other(format!(
+                        "a record of {} bytes would grow the file past its limit of {} bytes",
+                        record_len, limit
+                    )));
+                }
+            }
+        }
+
+        let mark = self.pending.len();
+        let mut position = self.f.seek(SeekFrom::End(0))? + self.pending.len() as u64;
+        if position == 0 {
+            self.crc.encode_header(&mut self.pending);
+            position = self.pending.len() as u64;
+        }
+        let padding = padding_for(position, self.alignment);
+        if padding > 0 {
+            encode_filler(padding, &mut self.pending);
+            position += padding;
+        }
+        encode_record_with(self.crc, key, value, &mut self.pending);
+        self.pending_count += 1;
+        if self.pending_count >= self.flush_every_n {
+            if let Err(err) = self.flush() {
+                // this record was never written; earlier ones stay buffered
+                self.pending.truncate(mark);
+                self.pending_count -= 1;
+                return Err(err);
+            }
+        }
+        Ok(position)
+    }
+
+    /// Applies a batch of writes, where an empty value deletes the key as in
+    /// `insert`. Repeated writes to a key are collapsed first, so only its
+    /// final value reaches the disk.
+    pub fn insert_batch(&mut self, pairs: &[(ByteString, ByteString)]) -> io::Result<()> {
+        let mut order = Vec::new();
+        let mut last = HashMap::new();
+        for (key, value) in pairs {
+            if last.insert(key.as_slice(), value.as_slice()).is_none() {
+                order.push(key.as_slice());
+            }
+        }
+
+        for key in order {
+            self.insert(key, last[key])?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn update<K: AsRef<ByteStr>>(&mut self, key: K, value: &ByteStr) -> io::Result<()> {
+        self.insert(key, value)
+    }
+
+    pub fn delete<K: AsRef<ByteStr>>(&mut self, key: K) -> io::Result<()> {
+        let key = key.as_ref();
+        if self.free_list.is_some() {
+            self.check_in_place()?;
+        }
+        let position = self.index.remove(key);
+        if self.free_list.is_none() {
+            self.insert_but_ignore_index(key, b"")?;
+            return self.log_index(key, None);
+        }
+        match position {
+            Some(position) => {
+                self.free_record(position)?;
+                self.log_index(key, None)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Cuts the file back to `offset`, which must be the start of a record
+    /// (or the end of the file), and rebuilds the index from what is left.
+    /// Meant for manual recovery when the last good offset is known.
+    pub fn truncate_to(&mut self, offset: u64) -> io::Result<()> {
+        self.flush()?;
+        let file_len = self.f.seek(SeekFrom::End(0))?;
+        let mut position = 0;
+        {
+            let mut f = BufReader::new(&mut self.f);
+            f.seek(SeekFrom::Start(0))?;
+            while position < offset && position < file_len {
+                let mut header = [0; HEADER_LEN as usize];
+                f.read_exact(&mut header)?;
+                let (_, key_len, val_len) = parse_header(&header);
+                let (key_len, val_len) = (key_len as u64, val_len as u64);
+                f.seek_relative((key_len + val_len) as i64)?;
+                position += HEADER_LEN + key_len + val_len;
+            }
+        }
+        if position != offset {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("offset {} is not on a record boundary", offset),
+            ));
+        }
+
+        warn!("truncating {} bytes from offset {}", file_len - offset, offset);
+        self.f.set_len(offset)?;
+        self.index.clear();
+        if self.free_list.is_some() {
+            self.set_free_list(true)?;
+        }
+        self.load()?;
+        if self.index_log.is_some() {
+            self.save_index()?;
+        }
+        Ok(())
+    }
+
+    /// Counts live values per power-of-two size class. A value of `n` bytes
+    /// is counted under `n.next_power_of_two()`, so the bucket `64` holds
+    /// values of 33 to 64 bytes. Only record headers are read.
+    pub fn value_size_histogram(&mut self) -> io::Result<BTreeMap<u64, usize>> {
+        self.flush()?;
+        let mut positions: Vec<u64> = self.index.values().collect();
+        positions.sort_unstable();
+
+        let mut histogram = BTreeMap::new();
+        for position in positions {
+            self.f.seek(SeekFrom::Start(position + 8))?;
+            let val_len = self.f.read_u32::<LittleEndian>()? as u64;
+            *histogram.entry(val_len.next_power_of_two()).or_insert(0) += 1;
+        }
+        Ok(histogram)
+    }
+
+    /// Counts live keys by their exact length in bytes. Only the index is
+    /// consulted.
+    pub fn key_length_histogram(&self) -> BTreeMap<usize, usize> {
+        let mut histogram = BTreeMap::new();
+        for key in self.index.keys() {
+            *histogram.entry(key.len()).or_insert(0) += 1;
+        }
+        histogram
+    }
+
+    /// Stores `value` under `key`, each as 8 bytes. The key is always
+    /// big-endian, whatever the rest of the file uses, so that the byte-wise
+    /// order of keys, and with it every range scan, is their numeric order.
+    /// The value is little-endian like the record headers, so a counter
+    /// kept by `increment` reads back here as long as it stays positive.
+    pub fn insert_u64(&mut self, key: u64, value: u64) -> io::Result<()> {
+        self.insert(key.to_be_bytes(), &value.to_le_bytes())
+    }
+
+    /// Reads a value written by `insert_u64`.
+    pub fn get_u64(&mut self, key: u64) -> io::Result<Option<u64>> {
+        let value = match self.get(key.to_be_bytes())? {
+            None => return Ok(None),
+            Some(value) => value,
+        };
+        let bytes: [u8; 8] = value.as_slice().try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "value is not a u64")
+        })?;
+        Ok(Some(u64::from_le_bytes(bytes)))
+    }
+
+    /// Adds `by` to the counter under `key`, a little-endian `i64` that
+    /// starts out at 0, and returns the new count. Taking `&mut self`, the
+    /// read and the write can't interleave with another update. Fails with
+    /// `InvalidData` if the value isn't 8 bytes long, and `InvalidInput` if
+    /// the count would overflow; the stored count is unchanged either way.
+    pub fn increment<K: AsRef<ByteStr>>(&mut self, key: K, by: i64) -> io::Result<i64> {
+        let key = key.as_ref();
+        let count = match self.get(key)? {
+            None => 0,
+            Some(value) => {
+                let bytes: [u8; 8] = value.as_slice().try_into().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "value is not an i64 counter")
+                })?;
+                i64::from_le_bytes(bytes)
+            }
+        };
+        let count = count.checked_add(by).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("adding {} to {} overflows the counter", by, count),
+            )
+        })?;
+        self.insert(key, &count.to_le_bytes())?;
+        Ok(count)
+    }
+
+    /// Makes room in the index for `additional` more keys, e.g. ahead of a
+    /// bulk insert.
+    pub fn reserve(&mut self, additional: usize) {
+        self.index.reserve(additional);
+    }
+
+    /// Gives back the memory the index no longer needs, e.g. after deleting
+    /// many keys.
+    pub fn shrink_index(&mut self) {
+        self.index.shrink_to_fit();
+    }
+
+    /// Rough number of bytes held by `self.index`. For the `HashMap`, one
+    /// `(key, offset)` slot plus a control byte per bucket of the table,
+    /// plus the heap buffers of the keys themselves; for front-coded keys,
+    /// the encoded blocks and the map of them. Allocator overhead is not
+    /// included.
+    pub fn index_memory_bytes(&self) -> usize {
+        self.index.memory_bytes()
+    }
+
+    /// Writes every live record to `w` in ascending key order, in the same
+    /// format as the data file, so the output can be opened as a store of its
+    /// own. Only the index is sorted; records are read and written one at a
+    /// time.
+    pub fn export_sorted<W: Write>(&mut self, w: W) -> io::Result<()> {
+        let mut w = BufWriter::new(w);
+        let mut header = Vec::new();
+        self.crc.encode_header(&mut header);
+        w.write_all(&header)?;
+        for position in self.positions_by_key(|_| true) {
+            let kv = self.get_at(position)?;
+            write_record(&mut w, self.crc, &kv.key, &kv.value)?;
+        }
+        w.flush()
+    }
+
+    /// A hash of the live contents, for telling whether two stores hold the
+    /// same keys and values. Pairs are hashed in byte order of their keys,
+    /// so neither the layout of the file (dead records, fillers, write order)
+    /// nor the key comparator matters.
+    pub fn fingerprint(&mut self) -> io::Result<u64> {
+        let mut hasher = Sha256::new();
+        for position in self.positions_sorted(|_| true, &bytewise) {
+            let kv = self.get_at(position)?;
+            hasher.update((kv.key.len() as u64).to_le_bytes());
+            hasher.update(&kv.key);
+            hasher.update((kv.value.len() as u64).to_le_bytes());
+            hasher.update(&kv.value);
+        }
+        let digest = hasher.finalize();
+        Ok(u64::from_le_bytes(digest[..8].try_into().unwrap_or_default()))
+    }
+
+    /// Iterates over the records with keys in `start..end`, in key order.
+    /// Only the matching index entries are collected up front; each record is
+    /// read from the file when the iterator reaches it. An empty `start` or
+    /// `end` leaves that end of the range open, as in `range_count`.
+    pub fn range_iter(
+        &mut self,
+        start: &ByteStr,
+        end: &ByteStr,
+    ) -> impl Iterator<Item = io::Result<KeyValuePair>> + '_ {
+        let compare = self.compare.0.clone();
+        let positions = self.positions_by_key(|key| {
+            (start.is_empty() || compare(start, key) != Ordering::Greater)
+                && (end.is_empty() || compare(key, end) == Ordering::Less)
+        });
+        positions.into_iter().map(move |position| self.get_at(position))
+    }
+
+    /// How many keys lie in `start..end`, in the order `range_iter` uses,
+    /// counted from the index without reading the file. An empty `start` or
+    /// `end` leaves that end of the range open; empty keys can't be stored,
+    /// so nothing is lost by that.
+    pub fn range_count(&self, start: &ByteStr, end: &ByteStr) -> usize {
+        let compare = self.compare.0.clone();
+        self.index
+            .keys()
+            .filter(|key| start.is_empty() || compare(start, key) != Ordering::Greater)
+            .filter(|key| end.is_empty() || compare(key, end) == Ordering::Less)
+            .count()
+    }
+
+    /// One page of a listing in key order: skips the first `offset` keys and
+    /// returns up to `limit` records after them. Past the last key the page
+    /// is empty.
+    pub fn scan(&mut self, offset: usize, limit: usize) -> io::Result<Vec<KeyValuePair>> {
+        let positions = self.positions_by_key(|_| true);
+        positions
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|position| self.get_at(position))
+            .collect()
+    }
+
+    /// Iterates over the live values in key order. Each record's key is
+    /// skipped over rather than read, which also means the values are not
+    /// checked against their checksums.
+    pub fn values(&mut self) -> impl Iterator<Item = io::Result<ByteString>> + '_ {
+        let positions = self.positions_by_key(|_| true);
+        positions.into_iter().map(move |position| self.value_at(position))
+    }
+
+    /// Reads every live record into a map, e.g. to compare a store with
+    /// the one it was made from by `from_hashmap`.
+    pub fn to_hashmap(&mut self) -> io::Result<HashMap<ByteString, ByteString>> {
+        let mut positions: Vec<u64> = self.index.values().collect();
+        positions.sort_unstable();
+        let mut map = HashMap::with_capacity(positions.len());
+        for position in positions {
+            let kv = self.get_at(position)?;
+            map.insert(kv.key, kv.value);
+        }
+        Ok(map)
+    }
+
+    // the value of the record at `position`, without reading its key
+    fn value_at(&mut self, position: u64) -> io::Result<ByteString> {
+        self.check_file()?;
+        self.flush()?;
+        self.f.seek(SeekFrom::Start(position))?;
+        let mut header = [0; HEADER_LEN as usize];
+        self.f.read_exact(&mut header)?;
+        let (_, key_len, val_len) = parse_header(&header);
+        self.f.seek(SeekFrom::Current(key_len as i64))?;
+        let mut value = vec![0; val_len as usize];
+        self.f.read_exact(&mut value)?;
+        Ok(value)
+    }
+
+    // record offsets of the live keys that `keep` accepts, sorted by key
+    fn positions_by_key<F: Fn(&ByteStr) -> bool>(&self, keep: F) -> Vec<u64> {
+        self.positions_sorted(keep, &*self.compare.0)
+    }
+
+    fn positions_sorted<F, C>(&self, keep: F, compare: &C) -> Vec<u64>
+    where
+        F: Fn(&ByteStr) -> bool,
+        C: Fn(&ByteStr, &ByteStr) -> Ordering + ?Sized,
+    {
+        let mut entries: Vec<(Cow<ByteStr>, u64)> =
+            self.index.iter().filter(|(k, _)| keep(k)).collect();
+        entries.sort_unstable_by(|a, b| compare(&a.0, &b.0));
+        entries.into_iter().map(|(_, position)| position).collect()
+    }
+
+    /// Live keys matching the shell-style `pattern`, in key order: `*` stands
+    /// for any run of characters, `?` for exactly one, and everything else
+    /// for itself. Keys that aren't valid UTF-8 never match. Only the index
+    /// is consulted.
+    pub fn keys_matching(&self, pattern: &str) -> io::Result<Vec<ByteString>> {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let mut keys: Vec<ByteString> = self
+            .index
+            .keys()
+            .filter(|key| match std::str::from_utf8(key) {
+                Ok(key) => glob_matches(&pattern, &key.chars().collect::<Vec<_>>()),
+                Err(_) => false,
+            })
+            .map(Cow::into_owned)
+            .collect();
+        let compare = self.compare.0.clone();
+        keys.sort_unstable_by(|a, b| compare(a, b));
+        Ok(keys)
+    }
+
+    /// Counts live keys grouped by the portion before the first `delimiter`.
+    /// Keys without the delimiter form a group of their own. Only the index
+    /// is consulted, no values are read.
+    pub fn count_by_prefix(&self, delimiter: u8) -> HashMap<ByteString, usize> {
+        let mut counts = HashMap::new();
+        for key in self.index.keys() {
+            let prefix = key.split(|&b| b == delimiter).next().unwrap_or(&key);
+            *counts.entry(prefix.to_vec()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Writes a byte-for-byte copy of the data file to `dest`, stale records
+    /// and all, and returns the number of bytes copied. Unlike `compact`,
+    /// nothing is rewritten and the store stays as it is.
+    pub fn copy_to(&mut self, dest: &Path) -> io::Result<u64> {
+        self.check_file()?;
+        self.flush()?;
+        self.f.seek(SeekFrom::Start(0))?;
+        let mut out = File::create(dest)?;
+        let copied = io::copy(&mut self.f, &mut out)?;
+        out.sync_all()?;
+        Ok(copied)
+    }
+
+    /// Moves every key from `at` on, in key order, into the store at `dest`
+    /// and deletes it here, like `BTreeMap::split_off`. Returns the number
+    /// of keys moved. `dest` is created if it doesn't exist; a new file gets
+    /// this store's checksum variant.
+    pub fn split_off(&mut self, at: &ByteStr, dest: &Path) -> io::Result<usize> {
+        let mut other = ActionKV::open(dest)?;
+        other.set_crc_variant(self.crc);
+        other.load()?;
+
+        let compare = self.compare.0.clone();
+        let positions = self.positions_by_key(|key| compare(key, at) != Ordering::Less);
+        let mut moved = Vec::with_capacity(positions.len());
+        for position in positions {
+            let kv = self.get_at(position)?;
+            other.insert(&kv.key, &kv.value)?;
+            moved.push(kv.key);
+        }
+        // only delete once the copies are safely written
+        other.flush()?;
+        for key in &moved {
+            self.delete(key)?;
+        }
+        Ok(moved.len())
+    }
+
+    /// Keys of the live records whose value starts with `prefix`, in key
+    /// order. Unlike the key lookups this reads every live value, so it takes
+    /// time proportional to the size of the data, not of the index.
+    pub fn find_by_value_prefix(&mut self, prefix: &ByteStr) -> io::Result<Vec<ByteString>> {
+        let mut keys = Vec::new();
+        for position in self.positions_by_key(|_| true) {
+            let kv = self.get_at(position)?;
+            if kv.value.starts_with(prefix) {
+                keys.push(kv.key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// The first live record, in file order, for which `f(key, value)` is
+    /// true. Reading stops as soon as one is found.
+    pub fn find<F>(&mut self, mut f: F) -> io::Result<Option<KeyValuePair>>
+    where
+        F: FnMut(&ByteStr, &ByteStr) -> bool,
+    {
+        let mut positions: Vec<u64> = self.index.values().collect();
+        positions.sort_unstable();
+        for position in positions {
+            let kv = self.get_at(position)?;
+            if f(&kv.key, &kv.value) {
+                return Ok(Some(kv));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Deletes every live record for which `f(key, value)` is true and
+    /// returns them, in file order. All records are read before the first
+    /// delete, so `f` sees the store as it was.
+    pub fn drain_filter<F>(&mut self, mut f: F) -> io::Result<Vec<KeyValuePair>>
+    where
+        F: FnMut(&ByteStr, &ByteStr) -> bool,
+    {
+        let mut positions: Vec<u64> = self.index.values().collect();
+        positions.sort_unstable();
+        let mut drained = Vec::new();
+        for position in positions {
+            let kv = self.get_at(position)?;
+            if f(&kv.key, &kv.value) {
+                drained.push(kv);
+            }
+        }
+        for kv in &drained {
+            self.delete(&kv.key)?;
+        }
+        Ok(drained)
+    }
+
+    // Writes `value` over the current value of `key` if they are the same
+    // length, returning whether it did.
+    fn overwrite(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<bool> {
+        self.check_in_place()?;
+        let position = match self.index.get(key) {
+            None => return Ok(false),
+            Some(&position) => position,
+        };
+        self.check_file()?;
+        self.flush()?;
+        self.f.seek(SeekFrom::Start(position))?;
+        let mut header = [0; HEADER_LEN as usize];
+        self.f.read_exact(&mut header)?;
+        let (_, _, val_len) = parse_header(&header);
+        if val_len as usize != value.len() {
+            return Ok(false);
+        }
+
+        let mut buf = Vec::new();
+        encode_record_with(self.crc, key, value, &mut buf);
+        self.f.seek(SeekFrom::Start(position))?;
+        self.f.write_all(&buf)?;
+        Ok(true)
+    }
+
+    /// Turns the record at `position` into a filler and adds it to the free list.
+    fn free_record(&mut self, position: u64) -> io::Result<()> {
+        self.check_in_place()?;
+        self.flush()?;
+        self.f.seek(SeekFrom::Start(position))?;
+        let mut header = [0; HEADER_LEN as usize];
+        self.f.read_exact(&mut header)?;
+        let (_, key_len, val_len) = parse_header(&header);
+        let len = HEADER_LEN + key_len as u64 + val_len as u64;
+
+        self.write_filler(position, len)?;
+        if let Some(free) = &mut self.free_list {
+            free.insert(position, len);
+        }
+        Ok(())
     }
 
-    let value = data.split_off(key_len as usize);
-    let key = data;
-    return Ok(KeyValuePair { key, value });
+    /// Claims a slot that fits `record_len` bytes exactly, or leaves enough
+    /// room behind the record to hold the header of a new, smaller filler.
+    fn take_free_slot(&mut self, record_len: u64) -> io::Result<Option<u64>> {
+        if self.free_list.is_none() {
+            return Ok(None);
+        }
+        self.check_in_place()?;
+        let alignment = self.alignment;
+        let free = match &mut self.free_list {
+            Some(free) => free,
+            None => return Ok(None),
+        };
+        let slot = free
+            .iter()
+            .map(|(&position, &len)| (position, len))
+            .filter(|&(position, _)| position % alignment == 0)
+            .find(|&(_, len)| len == record_len || len >= record_len + HEADER_LEN);
+        let (position, len) = match slot {
+            Some(slot) => slot,
+            None => return Ok(None),
+        };
+
+        free.remove(&position);
+        if len > record_len {
+            let rest = position + record_len;
+            free.insert(rest, len - record_len);
+            self.write_filler(rest, len - record_len)?;
+        }
+        Ok(Some(position))
+    }
+
+    // in-place writes would end up at the end of a file opened for appending
+    fn check_in_place(&self) -> io::Result<()> {
+        if self.append_only {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the file is opened append-only, records can't be written in place",
+            ));
+        }
+        Ok(())
+    }
+
+    fn write_filler(&mut self, position: u64, len: u64) -> io::Result<()> {
+        debug_assert!(len >= HEADER_LEN);
+        self.f.seek(SeekFrom::Start(position))?;
+        self.f.write_u32::<LittleEndian>(0)?;
+        self.f.write_u32::<LittleEndian>(0)?;
+        self.f.write_u32::<LittleEndian>((len - HEADER_LEN) as u32)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    pub(crate) fn temp_db(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("akv-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    pub(crate) fn file_len(path: &Path) -> u64 {
+        std::fs::metadata(path).unwrap().len()
+    }
+
+    fn core_suite<S: Storage>(store: &mut ActionKV<S>) {
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.update(b"a", b"3").unwrap();
+        store.delete(b"b").unwrap();
+        store.insert_batch(&[(b"c".to_vec(), b"4".to_vec())]).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"4".to_vec()));
+
+        let keys: Vec<ByteString> = store.range_iter(b"a", b"z").map(|kv| kv.unwrap().key).collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"c".to_vec()]);
+
+        let mut out = Vec::new();
+        assert_eq!(store.get_stream(b"c", &mut out).unwrap(), Some(1));
+        assert_eq!(out, b"4");
+
+        store.index.clear();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 2);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn core_suite_on_file() {
+        let path = temp_db("suite-file");
+        core_suite(&mut ActionKV::open(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn core_suite_on_cursor() {
+        core_suite(&mut ActionKV::with_storage(Cursor::new(Vec::new())));
+
+        let mut buf = Vec::new();
+        core_suite(&mut ActionKV::with_storage(Cursor::new(&mut buf)));
+        let mut store = ActionKV::with_storage(Cursor::new(&mut buf));
+        store.load().unwrap();
+        assert_eq!(store.get(b"c").unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn drain_filter_returns_what_it_deletes() {
+        let path = temp_db("drain-filter");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"tmp:a", b"1").unwrap();
+        store.insert(b"keep", b"2").unwrap();
+        store.insert(b"tmp:b", b"3").unwrap();
+        store.insert(b"tmp:a", b"4").unwrap();
+        store.insert(b"tmpfile", b"5").unwrap();
+
+        let drained = store.drain_filter(|key, _| key.starts_with(b"tmp:")).unwrap();
+        let pairs: Vec<(&[u8], &[u8])> =
+            drained.iter().map(|kv| (&kv.key[..], &kv.value[..])).collect();
+        assert_eq!(pairs, vec![(&b"tmp:b"[..], &b"3"[..]), (b"tmp:a", b"4")]);
+        assert!(drained.iter().all(|kv| kv.verify()));
+        assert!(store.drain_filter(|key, _| key.starts_with(b"tmp:")).unwrap().is_empty());
+
+        let mut reopened = ActionKV::open(&path).unwrap();
+        reopened.load().unwrap();
+        assert_eq!(reopened.index.len(), 2);
+        assert_eq!(reopened.get(b"keep").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(reopened.get(b"tmpfile").unwrap(), Some(b"5".to_vec()));
+        assert_eq!(reopened.get(b"tmp:a").unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn get_or_error_reports_missing_keys() {
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        store.insert(b"a", b"1").unwrap();
+        assert_eq!(store.get_or_error(b"a").unwrap(), b"1");
+        let err = store.get_or_error(b"b").unwrap_err();
+        assert!(matches!(err, ActionKVError::KeyNotFound));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn insert_get_delete_survive_reopen() {
+        let path = temp_db("basic");
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            store.insert(b"a", b"1").unwrap();
+            store.insert(b"b", b"2").unwrap();
+            store.update(b"a", b"3").unwrap();
+            store.delete(b"b").unwrap();
+            assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+            assert_eq!(store.get(b"b").unwrap(), None);
+        }
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn counts_keys_by_prefix() {
+        let path = temp_db("prefix");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a:1", b"x").unwrap();
+        store.insert(b"a:2", b"x").unwrap();
+        store.insert(b"b:1", b"x").unwrap();
+
+        let counts = store.count_by_prefix(b':');
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&b"a".to_vec()], 2);
+        assert_eq!(counts[&b"b".to_vec()], 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn counters_go_up_and_down() {
+        let path = temp_db("increment");
+        let mut store = ActionKV::open(&path).unwrap();
+        assert_eq!(store.increment(b"hits", 1).unwrap(), 1);
+        assert_eq!(store.increment(b"hits", 41).unwrap(), 42);
+        assert_eq!(store.increment(b"hits", -50).unwrap(), -8);
+        assert_eq!(store.get(b"hits").unwrap(), Some((-8i64).to_le_bytes().to_vec()));
+        assert_eq!(store.increment(b"misses", -3).unwrap(), -3);
+
+        store.insert(b"max", &i64::MAX.to_le_bytes()).unwrap();
+        let err = store.increment(b"max", 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(store.increment(b"max", 0).unwrap(), i64::MAX);
+        store.insert(b"name", b"not a number").unwrap();
+        let err = store.increment(b"name", 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut reopened = ActionKV::open(&path).unwrap();
+        reopened.load().unwrap();
+        assert_eq!(reopened.increment(b"hits", 8).unwrap(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn u64_round_trip_in_numeric_order() {
+        let path = temp_db("u64");
+        let mut store = ActionKV::open(&path).unwrap();
+        let ids = [300, 1, u64::MAX, 256, 0];
+        for &id in &ids {
+            store.insert_u64(id, id / 2).unwrap();
+        }
+        for &id in &ids {
+            assert_eq!(store.get_u64(id).unwrap(), Some(id / 2));
+        }
+        assert_eq!(store.get_u64(42).unwrap(), None);
+
+        let mut keys: Vec<_> = store.index.keys().map(Cow::into_owned).collect();
+        keys.sort();
+        let decoded: Vec<u64> = keys
+            .iter()
+            .map(|k| u64::from_be_bytes(k.as_slice().try_into().unwrap()))
+            .collect();
+        assert_eq!(decoded, vec![0, 1, 256, 300, u64::MAX]);
+
+        // values are little-endian, the same as counters
+        assert_eq!(store.get(300u64.to_be_bytes()).unwrap(), Some(150u64.to_le_bytes().to_vec()));
+        store.increment(300u64.to_be_bytes(), 2).unwrap();
+        assert_eq!(store.get_u64(300).unwrap(), Some(152));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn batch_collapses_repeated_writes() {
+        let path = temp_db("batch");
+        let mut store = ActionKV::open(&path).unwrap();
+        let mut batch: Vec<_> = (0..10u8).map(|i| (b"k".to_vec(), vec![i])).collect();
+        batch.insert(0, (b"j".to_vec(), b"".to_vec()));
+        batch.push((b"j".to_vec(), b"v".to_vec()));
+        store.insert_batch(&batch).unwrap();
+
+        // one record for each key: 12 byte header, 1 byte key, 1 byte value
+        assert_eq!(file_len(&path), 2 * 14);
+        assert_eq!(store.get(b"k").unwrap(), Some(vec![9]));
+        assert_eq!(store.get(b"j").unwrap(), Some(b"v".to_vec()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn get_many_cow_matches_get() {
+        let path = temp_db("many-cow");
+        let mut file = ActionKV::open(&path).unwrap();
+        let mut memory = ActionKV::with_storage(Cursor::new(Vec::new()));
+        for i in 0..10u8 {
+            file.insert([i], &[i; 3]).unwrap();
+            memory.insert([i], &[i; 3]).unwrap();
+        }
+        file.insert([2], b"again").unwrap();
+        memory.insert([2], b"again").unwrap();
+        let keys = [[7], [2], [42], [0]];
+
+        let mut expected = Vec::new();
+        for key in &keys {
+            expected.push(file.get(key).unwrap());
+        }
+        let owned = file.get_many_cow(&keys).unwrap();
+        assert!(owned.iter().flatten().all(|v| matches!(v, Cow::Owned(_))));
+        let owned: Vec<_> = owned.into_iter().map(|v| v.map(Cow::into_owned)).collect();
+        assert_eq!(owned, expected);
+
+        let borrowed = memory.get_many_cow(&keys).unwrap();
+        assert!(borrowed.iter().flatten().all(|v| matches!(v, Cow::Borrowed(_))));
+        let borrowed: Vec<_> = borrowed.into_iter().map(|v| v.map(Cow::into_owned)).collect();
+        assert_eq!(borrowed, expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn get_cow_borrows_from_in_memory_storage() {
+        let path = temp_db("cow");
+        let mut file = ActionKV::open(&path).unwrap();
+        let mut memory = ActionKV::with_storage(Cursor::new(Vec::new()));
+        file.insert(b"k", b"value").unwrap();
+        memory.insert(b"k", b"value").unwrap();
+
+        let owned = file.get_cow(b"k").unwrap().unwrap();
+        assert!(matches!(owned, Cow::Owned(_)));
+        let borrowed = memory.get_cow(b"k").unwrap().unwrap();
+        assert!(matches!(borrowed, Cow::Borrowed(_)));
+        assert_eq!(owned, borrowed);
+        assert!(memory.get_cow(b"missing").unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tampered_pairs_fail_verification() {
+        let path = temp_db("verify");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"k", b"value").unwrap();
+        let position = store.index[&b"k".to_vec()];
+
+        let mut kv = store.get_at(position).unwrap();
+        assert!(kv.verify());
+        assert_eq!(kv.checksum(), KeyValuePair::new(b"k".to_vec(), b"value".to_vec()).checksum());
+        kv.value[0] ^= 0xff;
+        assert!(!kv.verify());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_reports_progress_up_to_file_len() {
+        let path = temp_db("progress");
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            for i in 0..100u64 {
+                store.insert_u64(i, i).unwrap();
+            }
+        }
+
+        let mut store = ActionKV::open(&path).unwrap();
+        let mut calls = Vec::new();
+        store.load_with_progress(|read, len| calls.push((read, len))).unwrap();
+
+        let len = file_len(&path);
+        assert!(calls.len() > 1);
+        assert!(calls.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(calls.iter().all(|&(_, l)| l == len));
+        assert_eq!(calls.last(), Some(&(len, len)));
+        assert_eq!(store.index.len(), 100);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn deterministic_hashing_gives_stable_key_order() {
+        let path = temp_db("hasher");
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            for i in 0..50u64 {
+                store.insert_u64(i, i).unwrap();
+            }
+        }
+
+        let keys = || {
+            let mut store = ActionKV::open(&path).unwrap();
+            store.set_deterministic_hashing(true);
+            store.load().unwrap();
+            store.index.keys().map(Cow::into_owned).collect::<Vec<_>>()
+        };
+        assert_eq!(keys(), keys());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncate_to_record_boundaries_only() {
+        let path = temp_db("truncate");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        let boundary = store.index[&b"b".to_vec()];
+        store.insert(b"c", b"3").unwrap();
+
+        let err = store.truncate_to(boundary + 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(store.index.len(), 3);
+
+        store.truncate_to(boundary).unwrap();
+        assert_eq!(file_len(&path), boundary);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn value_sizes_land_in_power_of_two_buckets() {
+        let path = temp_db("histogram");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", &[0; 1]).unwrap();
+        store.insert(b"b", &[0; 3]).unwrap();
+        store.insert(b"c", &[0; 4]).unwrap();
+        store.insert(b"d", &[0; 100]).unwrap();
+        store.insert(b"e", &[0; 9]).unwrap();
+        store.update(b"e", &[0; 2]).unwrap();
+
+        let histogram = store.value_size_histogram().unwrap();
+        let expected: BTreeMap<u64, usize> = vec![(1, 1), (2, 1), (4, 2), (128, 1)]
+            .into_iter()
+            .collect();
+        assert_eq!(histogram, expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn key_lengths_are_counted_exactly() {
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        for key in &["abc", "xyz", "hello", "gone"] {
+            store.insert(key, b"v").unwrap();
+        }
+        store.delete("gone").unwrap();
+
+        let expected: BTreeMap<usize, usize> = vec![(3, 2), (5, 1)].into_iter().collect();
+        assert_eq!(store.key_length_histogram(), expected);
+    }
+
+    #[test]
+    fn lossy_key_display_keeps_exact_bytes() {
+        let path = temp_db("key-display");
+        let mut store = ActionKV::open(&path).unwrap();
+        let key: &[u8] = b"caf\xe9";
+        store.insert(key, b"latte").unwrap();
+
+        let shown = KeyDisplay(key);
+        assert_eq!(shown.to_string(), "caf\u{fffd}");
+        assert_eq!(store.get(shown.bytes()).unwrap(), Some(b"latte".to_vec()));
+        assert_eq!(store.get("caf\u{fffd}".as_bytes()).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn every_nth_insert_flushes() {
+        let path = temp_db("flush-every-n");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_flush_every_n(3);
+
+        // each record is 12 + 8 + 8 bytes
+        let mut lens = Vec::new();
+        for i in 0..7u64 {
+            store.insert_u64(i, i).unwrap();
+            lens.push(file_len(&path));
+        }
+        assert_eq!(lens, vec![0, 0, 84, 84, 84, 168, 168]);
+
+        assert_eq!(store.get_u64(6).unwrap(), Some(6));
+        assert_eq!(file_len(&path), 196);
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 7);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn streamed_corruption_surfaces_at_the_end() {
+        let path = temp_db("stream");
+        let value: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"big", &value).unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(store.get_stream(b"big", &mut out).unwrap(), Some(100_000));
+        assert_eq!(out, value);
+        assert_eq!(store.get_stream(b"none", &mut out).unwrap(), None);
+
+        let mut f = OpenOptions::new().write(true).open(&path).unwrap();
+        f.seek(SeekFrom::Start(HEADER_LEN + 3 + 50_000)).unwrap();
+        f.write_all(&[!value[50_000]]).unwrap();
+
+        let mut out = Vec::new();
+        let err = store.get_stream(b"big", &mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(out.len(), value.len());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unflushed_records_can_be_discarded_on_drop() {
+        let path = temp_db("no-flush-on-drop");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"written", b"1").unwrap();
+        store.set_flush_every_n(10);
+        store.insert(b"buffered", b"2").unwrap();
+        store.set_flush_on_drop(false);
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert!(store.contains_key(b"written"));
+        assert!(!store.contains_key(b"buffered"));
+
+        store.set_flush_every_n(10);
+        store.insert(b"cancelled", b"3").unwrap();
+        store.cancel();
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert!(!store.contains_key(b"cancelled"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn index_memory_estimate_grows_with_keys() {
+        let path = temp_db("index-memory");
+        let mut store = ActionKV::open(&path).unwrap();
+        assert_eq!(store.index_memory_bytes(), 0);
+
+        store.set_flush_every_n(1000);
+        let mut estimates = Vec::new();
+        for i in 0..4000u64 {
+            store.insert_u64(i, i).unwrap();
+            if (i + 1) % 1000 == 0 {
+                estimates.push(store.index_memory_bytes() as f64);
+            }
+        }
+        let per_key = estimates[3] / 4000.0;
+        assert!(per_key > 40.0);
+        assert!(estimates.windows(2).all(|w| w[0] < w[1]));
+        assert!(estimates[3] / estimates[1] < 2.5);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_only_files_fail_at_open() {
+        let path = temp_db("read-only");
+        ActionKV::open(&path).unwrap().insert(b"k", b"v").unwrap();
+        let mut permissions = std::fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&path, permissions).unwrap();
+
+        let err = ActionKV::open(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err = ActionKV::open_existing(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn removed_or_replaced_file_is_reported() {
+        let path = temp_db("removed");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"k", b"v").unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+        let err = store.get(b"k").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = store.get_stream(b"k", &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = store.insert(b"j", b"w").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        std::fs::write(&path, b"").unwrap();
+        let err = store.get(b"k").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        let err = store.get_stream(b"k", &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+
+        store.reopen().unwrap();
+        assert_eq!(store.get(b"k").unwrap(), None);
+        store.insert(b"k", b"v2").unwrap();
+        assert_eq!(store.get(b"k").unwrap(), Some(b"v2".to_vec()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn export_is_in_key_order() {
+        let path = temp_db("export");
+        let mut store = ActionKV::open(&path).unwrap();
+        for key in &[&b"pear"[..], b"apple", b"fig", b"banana"] {
+            store.insert(key, b"fruit").unwrap();
+        }
+        store.update(b"fig", b"dried").unwrap();
+
+        let mut out = Vec::new();
+        store.export_sorted(&mut out).unwrap();
+        let mut records = Vec::new();
+        let mut rest = out.as_slice();
+        while !rest.is_empty() {
+            records.push(process_record(&mut rest, CrcVariant::Ieee).unwrap());
+        }
+
+        let keys: Vec<&[u8]> = records.iter().map(|kv| kv.key.as_slice()).collect();
+        assert_eq!(keys, vec![&b"apple"[..], b"banana", b"fig", b"pear"]);
+        assert_eq!(records[2].value, b"dried");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn aligned_records_start_on_boundaries() {
+        let path = temp_db("aligned");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_alignment(8);
+        for i in 0..20usize {
+            store.insert(vec![b'k'; i + 1], &vec![b'v'; i * 3 + 1]).unwrap();
+        }
+        assert!(store.index.values().all(|position| position % 8 == 0));
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 20);
+        assert!(store.index.values().all(|position| position % 8 == 0));
+        for i in 0..20usize {
+            let value = store.get(vec![b'k'; i + 1]).unwrap();
+            assert_eq!(value, Some(vec![b'v'; i * 3 + 1]));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn range_iter_reads_in_range_records_lazily() {
+        let path = temp_db("range");
+        let mut store = ActionKV::open(&path).unwrap();
+        for key in &[&b"a"[..], b"b", b"c", b"d", b"e"] {
+            store.insert(key, key).unwrap();
+        }
+
+        let keys: Vec<ByteString> = store
+            .range_iter(b"b", b"e")
+            .map(|kv| kv.unwrap().key)
+            .collect();
+        assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+        assert_eq!(store.range_iter(b"x", b"z").count(), 0);
+        // an empty bound is open, as it is for `range_count`
+        let keys: Vec<ByteString> = store
+            .range_iter(b"d", b"")
+            .map(|kv| kv.unwrap().key)
+            .collect();
+        assert_eq!(keys, vec![b"d".to_vec(), b"e".to_vec()]);
+        assert_eq!(store.range_iter(b"", b"c").count(), 2);
+        assert_eq!(store.range_iter(b"", b"").count(), store.range_count(b"", b""));
+
+        // a damaged record only fails once the iterator gets to it
+        let position = store.index[&b"d".to_vec()];
+        let mut f = OpenOptions::new().write(true).open(&path).unwrap();
+        f.seek(SeekFrom::Start(position + HEADER_LEN)).unwrap();
+        f.write_all(b"X").unwrap();
+        let mut range = store.range_iter(b"b", b"e");
+        assert_eq!(range.next().unwrap().unwrap().key, b"b");
+        assert_eq!(range.next().unwrap().unwrap().key, b"c");
+        assert!(range.next().unwrap().is_err());
+        assert!(range.next().is_none());
+        drop(range);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn hashmaps_round_trip_through_a_store() {
+        let path = temp_db("hashmap");
+        let mut map = HashMap::new();
+        for i in 0..100u32 {
+            map.insert(i.to_be_bytes().to_vec(), vec![i as u8; i as usize % 7 + 1]);
+        }
+        map.insert(vec![0, 255], b"binary".to_vec());
+
+        let mut store = ActionKV::from_hashmap(&path, &map).unwrap();
+        assert_eq!(store.to_hashmap().unwrap(), map);
+        drop(store);
+        let mut reopened = ActionKV::open(&path).unwrap();
+        reopened.load().unwrap();
+        assert_eq!(reopened.to_hashmap().unwrap(), map);
+
+        // loading into an existing store overwrites and deletes
+        let mut changes = HashMap::new();
+        changes.insert(vec![0, 255], b"changed".to_vec());
+        changes.insert(0u32.to_be_bytes().to_vec(), Vec::new());
+        let mut store = ActionKV::from_hashmap(&path, &changes).unwrap();
+        map.insert(vec![0, 255], b"changed".to_vec());
+        map.remove(0u32.to_be_bytes().as_slice());
+        assert_eq!(store.to_hashmap().unwrap(), map);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn range_count_counts_without_reading_records() {
+        let path = temp_db("range-count");
+        let mut store = ActionKV::open(&path).unwrap();
+        for key in &[&b"a"[..], b"b", b"c", b"d", b"e"] {
+            store.insert(key, key).unwrap();
+        }
+        store.delete(b"c").unwrap();
+        // counting never gets as far as the damaged file
+        std::fs::write(&path, b"garbage").unwrap();
+
+        assert_eq!(store.range_count(b"b", b"e"), 2);
+        assert_eq!(store.range_count(b"a", b"b"), 1);
+        assert_eq!(store.range_count(b"aa", b"bb"), 1);
+        assert_eq!(store.range_count(b"e", b"z"), 1);
+        assert_eq!(store.range_count(b"x", b"z"), 0);
+        // empty and backwards ranges
+        assert_eq!(store.range_count(b"b", b"b"), 0);
+        assert_eq!(store.range_count(b"d", b"b"), 0);
+        // open ends
+        assert_eq!(store.range_count(b"", b"c"), 2);
+        assert_eq!(store.range_count(b"c", b""), 2);
+        assert_eq!(store.range_count(b"", b""), 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn range_scans_follow_the_key_comparator() {
+        // "item10" after "item9", by the number at the end
+        fn numeric(a: &ByteStr, b: &ByteStr) -> Ordering {
+            let number = |key: &ByteStr| -> u64 {
+                String::from_utf8_lossy(&key[4..]).parse().unwrap()
+            };
+            number(a).cmp(&number(b))
+        }
+
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        store.set_key_comparator(numeric);
+        for i in 1..=12 {
+            store.insert(format!("item{}", i), b"v").unwrap();
+        }
+
+        let keys: Vec<String> = store
+            .range_iter(b"item8", b"item11")
+            .map(|kv| String::from_utf8(kv.unwrap().key).unwrap())
+            .collect();
+        assert_eq!(keys, vec!["item8", "item9", "item10"]);
+
+        // a closure can carry its own settings, here the separator
+        let separator = b'/';
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        store.set_key_comparator(move |a: &ByteStr, b: &ByteStr| {
+            let number = |key: &ByteStr| -> u64 {
+                let digits = key.rsplit(|&byte| byte == separator).next().unwrap_or(key);
+                String::from_utf8_lossy(digits).parse().unwrap()
+            };
+            number(a).cmp(&number(b))
+        });
+        for i in 1..=12 {
+            store.insert(format!("item/{}", i), b"v").unwrap();
+        }
+        assert_eq!(store.range_count(b"item/2", b"item/10"), 8);
+        let keys = store.keys_matching("item/1*").unwrap();
+        let expected: Vec<ByteString> = ["item/1", "item/10", "item/11", "item/12"]
+            .iter()
+            .map(|key| key.as_bytes().to_vec())
+            .collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn scan_pages_through_the_keys() {
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        for key in b"abcdefg" {
+            store.insert([*key], b"v").unwrap();
+        }
+        let mut page = |offset, limit| -> Vec<ByteString> {
+            let records = store.scan(offset, limit).unwrap();
+            records.into_iter().map(|kv| kv.key).collect()
+        };
+
+        assert_eq!(page(0, 3), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(page(3, 3), vec![b"d".to_vec(), b"e".to_vec(), b"f".to_vec()]);
+        assert_eq!(page(6, 3), vec![b"g".to_vec()]);
+        assert!(page(7, 3).is_empty());
+        assert!(page(0, 0).is_empty());
+    }
+
+    #[test]
+    fn split_off_moves_the_upper_keys() {
+        let path = temp_db("split");
+        let dest = temp_db("split-dest");
+        let mut store = ActionKV::open(&path).unwrap();
+        for i in 0..20u64 {
+            store.insert_u64(i, i * 3).unwrap();
+        }
+
+        assert_eq!(store.split_off(&12u64.to_be_bytes(), &dest).unwrap(), 8);
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        let mut other = ActionKV::open(&dest).unwrap();
+        other.load().unwrap();
+        assert_eq!((store.index.len(), other.index.len()), (12, 8));
+        for i in 0..20u64 {
+            let (here, there) = (store.get_u64(i).unwrap(), other.get_u64(i).unwrap());
+            if i < 12 {
+                assert_eq!((here, there), (Some(i * 3), None));
+            } else {
+                assert_eq!((here, there), (None, Some(i * 3)));
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&dest).unwrap();
+    }
+
+    #[test]
+    fn keys_match_glob_patterns() {
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        for key in &["user:1:email", "user:22:email", "user:1:name", "admin", "añb"] {
+            store.insert(key, b"v").unwrap();
+        }
+        store.insert([0xff, b'a'], b"not utf-8").unwrap();
+        let matching = |pattern| -> Vec<String> {
+            let keys = store.keys_matching(pattern).unwrap();
+            keys.into_iter().map(|k| String::from_utf8(k).unwrap()).collect()
+        };
+
+        assert_eq!(matching("user:*:email"), vec!["user:1:email", "user:22:email"]);
+        assert_eq!(matching("user:?:*"), vec!["user:1:email", "user:1:name"]);
+        assert_eq!(matching("a?b"), vec!["añb"]);
+        assert_eq!(matching("admin"), vec!["admin"]);
+        assert_eq!(matching("*a"), Vec::<String>::new());
+        assert_eq!(matching("*").len(), 5);
+        assert!(matching("user").is_empty());
+    }
+
+    #[test]
+    fn same_size_updates_overwrite_in_place() {
+        let path = temp_db("in-place");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_overwrite_in_place(true);
+        store.insert(b"a", b"four").unwrap();
+        store.insert(b"b", b"1").unwrap();
+        let (position, len) = (store.index[&b"a".to_vec()], file_len(&path));
+
+        store.insert(b"a", b"FOUR").unwrap();
+        assert_eq!(file_len(&path), len);
+        assert_eq!(store.index[&b"a".to_vec()], position);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"FOUR".to_vec()));
+
+        store.insert(b"a", b"longer").unwrap();
+        assert!(file_len(&path) > len);
+        assert_ne!(store.index[&b"a".to_vec()], position);
+
+        let mut reopened = ActionKV::open(&path).unwrap();
+        reopened.load().unwrap();
+        assert_eq!(reopened.get(b"a").unwrap(), Some(b"longer".to_vec()));
+        assert_eq!(reopened.get(b"b").unwrap(), Some(b"1".to_vec()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_into_the_middle_land_at_their_offset() {
+        let path = temp_db("seek-then-write");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_free_list(true).unwrap();
+        store.insert(b"a", b"first").unwrap();
+        store.insert(b"b", b"second").unwrap();
+        let (position, len) = (store.index[&b"a".to_vec()], file_len(&path));
+        store.delete(b"a").unwrap();
+        store.insert(b"c", b"third").unwrap();
+        assert_eq!(store.index[&b"c".to_vec()], position);
+        assert_eq!(file_len(&path), len);
+
+        let bytes = std::fs::read(&path).unwrap();
+        let mut expected = Vec::new();
+        encode_record_with(store.crc, b"c", b"third", &mut expected);
+        let start = position as usize;
+        assert_eq!(&bytes[start..start + expected.len()], &expected[..]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn append_only_stores_refuse_in_place_writes() {
+        let path = temp_db("append-only");
+        let mut store = ActionKV::open_append_only(&path).unwrap();
+        store.insert(b"a", b"four").unwrap();
+        store.insert(b"b", b"1").unwrap();
+        store.delete(b"b").unwrap();
+        let len = file_len(&path);
+
+        store.set_overwrite_in_place(true);
+        let err = store.insert(b"a", b"FOUR").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        store.set_overwrite_in_place(false);
+        assert_eq!(file_len(&path), len);
+
+        let other_path = temp_db("append-only-free");
+        let mut other = ActionKV::open_append_only(&other_path).unwrap();
+        other.set_free_list(true).unwrap();
+        other.load().unwrap();
+        let err = other.insert(b"a", b"1").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(file_len(&other_path), 0);
+        std::fs::remove_file(&other_path).unwrap();
+
+        // appends go where the store expects them, and survive a reopen
+        store.insert(b"a", b"FOUR").unwrap();
+        store.reopen().unwrap();
+        store.insert(b"c", b"3").unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"FOUR".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+        let mut reopened = ActionKV::open(&path).unwrap();
+        reopened.load().unwrap();
+        assert_eq!(reopened.index, store.index);
+        assert!(!reopened.contains_key(b"b"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cloned_handles_read_independently() {
+        let path = temp_db("clone-handle");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_flush_every_n(100);
+        for i in 0..50u64 {
+            store.insert_u64(i, i * 7).unwrap();
+        }
+        let err = store.clone_handle().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        store.flush().unwrap();
+        let mut other = store.clone_handle().unwrap();
+        assert!(other.index.shares_keys_with(&store.index));
+        assert_eq!(other.index, store.index);
+
+        // interleaved reads from opposite ends of the file
+        for i in 0..50u64 {
+            assert_eq!(store.get_u64(i).unwrap(), Some(i * 7));
+            assert_eq!(other.get_u64(49 - i).unwrap(), Some((49 - i) * 7));
+        }
+        let mut values = store.values();
+        let mut others = other.values();
+        for _ in 0..50 {
+            assert_eq!(values.next().unwrap().unwrap(), others.next().unwrap().unwrap());
+        }
+        drop((values, others));
+
+        // the writer copies the index, the other keeps what it had
+        store.insert_u64(50, 350).unwrap();
+        store.delete(0u64.to_be_bytes()).unwrap();
+        assert!(!other.index.shares_keys_with(&store.index));
+        assert!(!other.contains_key(50u64.to_be_bytes()));
+        assert_eq!(other.get_u64(0).unwrap(), Some(0));
+        assert_eq!(store.get_u64(50).unwrap(), Some(350));
+        assert_eq!(store.get_u64(0).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn values_match_the_full_records() {
+        let path = temp_db("values");
+        let mut store = ActionKV::open(&path).unwrap();
+        for i in 0..20u8 {
+            store.insert([b'k', i], &vec![i; i as usize + 1]).unwrap();
+        }
+        store.insert([b'k', 3], b"overwritten").unwrap();
+        store.delete([b'k', 7]).unwrap();
+
+        let values: Vec<ByteString> = store.values().map(|v| v.unwrap()).collect();
+        let records: Vec<ByteString> = store
+            .range_iter(b"k", b"l")
+            .map(|kv| kv.unwrap().value)
+            .collect();
+        assert_eq!(values.len(), 19);
+        assert_eq!(values, records);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // in-memory storage whose writes fail once `budget` bytes have gone out
+    struct FailingStorage {
+        inner: Cursor<Vec<u8>>,
+        budget: usize,
+    }
+
+    impl Read for FailingStorage {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for FailingStorage {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.budget == 0 {
+                return Err(io::Error::other("disk full"));
+            }
+            let n = buf.len().min(self.budget);
+            self.budget -= n;
+            self.inner.write(&buf[..n])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for FailingStorage {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl Storage for FailingStorage {
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            self.inner.set_len(len)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_insert_leaves_no_torn_record() {
+        let storage = FailingStorage {
+            inner: Cursor::new(Vec::new()),
+            budget: usize::MAX,
+        };
+        let mut store = ActionKV::with_storage(storage);
+        store.insert(b"a", b"1").unwrap();
+        let len = store.f.inner.get_ref().len();
+
+        store.f.budget = 5;
+        assert!(store.insert(b"b", b"2").is_err());
+        assert_eq!(store.f.inner.get_ref().len(), len);
+        assert_eq!(store.get(b"b").unwrap(), None);
+
+        store.f.budget = usize::MAX;
+        store.insert(b"c", b"3").unwrap();
+        store.index.clear();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 2);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn preallocated_index_loads_without_growing() {
+        let path = temp_db("capacity");
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            for i in 0..1000u32 {
+                store.insert(i.to_be_bytes(), b"v").unwrap();
+            }
+        }
+
+        let mut store = ActionKV::open_with_capacity(&path, 1000).unwrap();
+        store.set_deterministic_hashing(true);
+        let capacity = store.index.capacity();
+        assert!(capacity >= 1000);
+        store.load().unwrap();
+        assert_eq!(store.index.capacity(), capacity);
+
+        let mut plain = ActionKV::open(&path).unwrap();
+        plain.load().unwrap();
+        assert_eq!(store.index.len(), plain.index.len());
+        for (key, position) in &plain.index {
+            assert_eq!(store.index.get(&key), Some(&position));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keys_accept_any_byte_container() {
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        store.insert("text", b"1").unwrap();
+        store.insert(String::from("owned"), b"2").unwrap();
+        store.insert(vec![0, 159, 146, 150], b"3").unwrap();
+
+        assert_eq!(store.get("text").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(String::from("text")).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(&b"owned"[..]).unwrap(), Some(b"2".to_vec()));
+        let owned: Vec<u8> = b"owned".to_vec();
+        assert_eq!(store.get(owned).unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get([0, 159, 146, 150]).unwrap(), Some(b"3".to_vec()));
+
+        assert!(store.contains_key("owned"));
+        store.delete("owned").unwrap();
+        assert!(!store.contains_key(String::from("owned")));
+    }
+
+    #[test]
+    fn files_are_read_with_the_crc_they_were_written_with() {
+        let path = temp_db("castagnoli");
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            store.set_crc_variant(CrcVariant::Castagnoli);
+            store.insert("a", b"1").unwrap();
+            store.insert("b", b"2").unwrap();
+            store.delete("a").unwrap();
+        }
+
+        let mut store = ActionKV::open(&path).unwrap();
+        assert_eq!(store.crc_variant(), CrcVariant::Castagnoli);
+        store.load().unwrap();
+        assert_eq!(store.get("b").unwrap(), Some(b"2".to_vec()));
+        assert!(store.get_at(store.index[&b"b"[..]]).unwrap().verify());
+        store.compact().unwrap();
+        store.reopen().unwrap();
+        assert_eq!(store.crc_variant(), CrcVariant::Castagnoli);
+        assert_eq!(store.get("b").unwrap(), Some(b"2".to_vec()));
+        drop(store);
+
+        // without its announcement the records no longer pass as IEEE
+        let bytes = std::fs::read(&path).unwrap();
+        let mut header = Vec::new();
+        CrcVariant::Castagnoli.encode_header(&mut header);
+        std::fs::write(&path, &bytes[header.len()..]).unwrap();
+        let mut store = ActionKV::open(&path).unwrap();
+        assert_eq!(store.crc_variant(), CrcVariant::Ieee);
+        assert_eq!(store.load().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn the_free_list_cannot_be_enabled_after_load() {
+        let path = temp_db("free-list-late");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"a", b"2").unwrap();
+        store.reopen().unwrap();
+
+        let err = store.set_free_list(true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        store.delete(b"a").unwrap();
+        store.reopen().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_freed_first_record_never_announces_a_crc() {
+        let path = temp_db("crc-lookalike");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_free_list(true).unwrap();
+        store.load().unwrap();
+        // freed, this reads like the filler announcing Castagnoli
+        store.insert("crc", b"32c").unwrap();
+        store.insert("b", b"2").unwrap();
+        store.delete("crc").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        assert_eq!(store.crc_variant(), CrcVariant::Ieee);
+        store.load().unwrap();
+        assert_eq!(store.get("b").unwrap(), Some(b"2".to_vec()));
+        assert!(!store.contains_key("crc"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn redacted_pairs_hide_their_values() {
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        store.insert("user", b"hunter2").unwrap();
+        let position = store.index[&b"user"[..]];
+
+        let shown = format!("{:?}", store.get_at(position).unwrap());
+        assert!(shown.contains(&format!("{:?}", b"hunter2".to_vec())));
+
+        store.set_redact_values(true);
+        let shown = format!("{:?}", store.get_at(position).unwrap());
+        assert!(shown.contains(&format!("{:?}", b"user".to_vec())));
+        assert!(shown.contains("<7 bytes>"));
+        assert!(!shown.contains(&format!("{:?}", b"hunter2".to_vec())));
+    }
+
+    #[test]
+    fn open_existing_requires_the_file() {
+        let path = temp_db("open-existing");
+        let err = ActionKV::open_existing(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!path.exists());
+
+        ActionKV::open(&path).unwrap().insert("k", b"v").unwrap();
+        assert!(path.exists());
+        let mut store = ActionKV::open_existing(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get("k").unwrap(), Some(b"v".to_vec()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn value_prefix_scan_finds_matching_values_only() {
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        store.insert("b", b"image/png").unwrap();
+        store.insert("a", b"image/jpeg").unwrap();
+        store.insert("c", b"text/plain").unwrap();
+        store.insert("image", b"text/html").unwrap();
+        store.insert("d", b"image/gif").unwrap();
+        store.delete("d").unwrap();
+
+        let keys = store.find_by_value_prefix(b"image/").unwrap();
+        assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+        assert!(store.find_by_value_prefix(b"audio/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn copy_to_keeps_every_byte() {
+        let path = temp_db("copy-src");
+        let dest = temp_db("copy-dest");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_flush_every_n(10);
+        store.insert("a", b"1").unwrap();
+        store.insert("a", b"2").unwrap();
+        store.delete("a").unwrap();
+        store.insert("b", b"3").unwrap();
+
+        let copied = store.copy_to(&dest).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), std::fs::read(&path).unwrap());
+        assert_eq!(copied, file_len(&path));
+
+        store.insert("c", b"4").unwrap();
+        assert_eq!(store.get("b").unwrap(), Some(b"3".to_vec()));
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&dest).unwrap();
+    }
+
+    #[test]
+    fn verify_key_notices_drifted_offsets() {
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        store.insert("a", b"1").unwrap();
+        store.insert("b", b"2").unwrap();
+        assert!(store.verify_key("a").unwrap());
+        assert!(store.verify_key("b").unwrap());
+        assert!(!store.verify_key("missing").unwrap());
+
+        let b = store.index[&b"b"[..]];
+        store.index.insert(b"a".to_vec(), b);
+        assert!(!store.verify_key("a").unwrap());
+        store.index.insert(b"a".to_vec(), b + 1);
+        assert!(!store.verify_key("a").unwrap());
+        store.index.insert(b"a".to_vec(), 1 << 20);
+        assert!(!store.verify_key("a").unwrap());
+    }
+
+    #[test]
+    fn find_stops_at_the_first_match() {
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        store.insert("small", b"1").unwrap();
+        store.insert("big", b"1000").unwrap();
+        store.insert("bigger", b"10000").unwrap();
+
+        let mut seen = 0;
+        let found = store
+            .find(|_, value| {
+                seen += 1;
+                value.len() > 3
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.key, b"big");
+        assert_eq!(seen, 2);
+        assert!(store.find(|key, _| key.is_empty()).unwrap().is_none());
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn stores_move_between_threads() {
+        assert_send_sync::<ActionKV>();
+        assert_send_sync::<ActionKV<Cursor<Vec<u8>>>>();
+
+        let path = temp_db("threads");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert("main", b"1").unwrap();
+        let writer = std::thread::spawn(move || {
+            for i in 0..100u64 {
+                store.insert_u64(i, i).unwrap();
+            }
+            store
+        });
+        let mut store = writer.join().unwrap();
+        assert_eq!(store.get("main").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get_u64(99).unwrap(), Some(99));
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn identical_blobs_are_stored_once() {
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        let hash = store.put_blob(b"the same bytes").unwrap();
+        let len = store.f.get_ref().len();
+        assert_eq!(store.put_blob(b"the same bytes").unwrap(), hash);
+        assert_eq!(store.f.get_ref().len(), len);
+
+        let other = store.put_blob(b"other bytes").unwrap();
+        assert_ne!(other, hash);
+        assert_eq!(hash.len(), 32);
+        assert_eq!(store.get_blob(&hash).unwrap(), Some(b"the same bytes".to_vec()));
+        assert_eq!(store.get_blob(&other).unwrap(), Some(b"other bytes".to_vec()));
+        assert_eq!(store.index.len(), 2);
+    }
+
+    #[test]
+    fn shrink_index_after_bulk_deletes() {
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        store.reserve(2000);
+        assert!(store.index.capacity() >= 2000);
+        for i in 0..1000u64 {
+            store.insert_u64(i, i).unwrap();
+        }
+        for i in 10..1000u64 {
+            store.delete(i.to_be_bytes()).unwrap();
+        }
+
+        let before = store.index.capacity();
+        store.shrink_index();
+        assert!(store.index.capacity() < before);
+        assert_eq!(store.index.len(), 10);
+        for i in 0..10u64 {
+            assert_eq!(store.get_u64(i).unwrap(), Some(i));
+        }
+    }
+
+    #[test]
+    fn files_without_checksums_ignore_the_checksum_bytes() {
+        let path = temp_db("no-checksums");
+        {
+            let mut store = ActionKV::open(&path).unwrap();
+            store.set_crc_variant(CrcVariant::None);
+            store.insert("a", b"1").unwrap();
+            store.insert("b", b"22").unwrap();
+        }
+
+        // scribble over the checksum of every record
+        let mut bytes = std::fs::read(&path).unwrap();
+        let mut store = ActionKV::open(&path).unwrap();
+        assert_eq!(store.crc_variant(), CrcVariant::None);
+        store.load().unwrap();
+        for position in store.index.values() {
+            let at = position as usize;
+            bytes[at..at + 4].copy_from_slice(&0xdead_beef_u32.to_le_bytes());
+        }
+        drop(store);
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(b"1".to_vec()));
+        let mut out = Vec::new();
+        assert_eq!(store.get_stream(b"b", &mut out).unwrap(), Some(2));
+        assert_eq!(out, b"22");
+        assert!(store.get_at(store.index[&b"b"[..]]).unwrap().verify());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fingerprint_follows_contents_not_layout() {
+        let path = temp_db("fingerprint");
+        let mut store = ActionKV::open(&path).unwrap();
+        for i in 0..20u64 {
+            store.insert_u64(i, i).unwrap();
+        }
+        for i in 0..5u64 {
+            store.insert_u64(i, i + 100).unwrap();
+        }
+        store.delete(7u64.to_be_bytes()).unwrap();
+        let before = store.fingerprint().unwrap();
+
+        store.compact().unwrap();
+        assert_eq!(store.fingerprint().unwrap(), before);
+
+        let mut copy = ActionKV::with_storage(Cursor::new(Vec::new()));
+        for i in (0..20u64).rev().filter(|&i| i != 7) {
+            copy.insert_u64(i, if i < 5 { i + 100 } else { i }).unwrap();
+        }
+        assert_eq!(copy.fingerprint().unwrap(), before);
+
+        copy.insert_u64(19, 20).unwrap();
+        assert_ne!(copy.fingerprint().unwrap(), before);
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn deleted_space_is_reused() {
+        let path = temp_db("free-list");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_free_list(true).unwrap();
+        store.load().unwrap();
+
+        store.insert(b"apple", b"red").unwrap();
+        store.insert(b"banana", b"yellow").unwrap();
+        let freed = store.index[&b"apple".to_vec()];
+        let len = file_len(&path);
+
+        store.delete(b"apple").unwrap();
+        store.insert(b"grape", b"red").unwrap();
+        assert_eq!(file_len(&path), len);
+        assert_eq!(store.index[&b"grape".to_vec()], freed);
+        assert_eq!(store.get(b"grape").unwrap(), Some(b"red".to_vec()));
+        assert_eq!(store.free_bytes(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn smaller_records_split_free_slots() {
+        let path = temp_db("free-split");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_free_list(true).unwrap();
+        store.load().unwrap();
+
+        store.insert(b"long", &[7; 64]).unwrap();
+        store.insert(b"tail", b"x").unwrap();
+        store.update(b"long", b"short").unwrap();
+        let len = file_len(&path);
+        store.insert(b"more", b"y").unwrap();
+        assert_eq!(file_len(&path), len);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_free_list(true).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"long").unwrap(), Some(b"short".to_vec()));
+        assert_eq!(store.get(b"tail").unwrap(), Some(b"x".to_vec()));
+        assert_eq!(store.get(b"more").unwrap(), Some(b"y".to_vec()));
+        assert!(store.free_bytes() > 0);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,204 @@
+use super::*;
+
+/// What `merge_databases` does with a key that appears in several inputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// The value from the input listed last is kept.
+    LastWins,
+    /// The value from the input listed first is kept.
+    FirstWins,
+    /// Merging fails with `InvalidData`.
+    Error,
+}
+
+/// Writes the live records of all `inputs` into a single compacted file at
+/// `output`, replacing whatever was there, and returns the number of keys
+/// written. The inputs are left untouched, even when `output` is one of
+/// them: the merge goes to a temporary file next to `output` that is only
+/// renamed over it at the end. A missing input fails with `NotFound`
+/// instead of being created empty.
+///
+/// The output keeps the checksum variant of the inputs when all of them
+/// that hold any keys agree on one, and falls back to the IEEE default
+/// for new files otherwise.
+pub fn merge_databases(
+    inputs: &[&Path],
+    output: &Path,
+    conflict: ConflictPolicy,
+) -> io::Result<usize> {
+    let mut stores = Vec::with_capacity(inputs.len());
+    for path in inputs {
+        let mut store = ActionKV::open_existing(path)?;
+        store.load()?;
+        stores.push(store);
+    }
+
+    // key -> position of the store its value comes from
+    let mut owners: BTreeMap<ByteString, usize> = BTreeMap::new();
+    for (i, store) in stores.iter().enumerate() {
+        for key in store.index.keys() {
+            match owners.get(&*key) {
+                None => {}
+                Some(_) if conflict == ConflictPolicy::LastWins => {}
+                Some(_) if conflict == ConflictPolicy::FirstWins => continue,
+                Some(&first) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "key {} is in both {} and {}",
+                            KeyDisplay(&key),
+                            inputs[first].display(),
+                            inputs[i].display()
+                        ),
+                    ))
+                }
+            }
+            owners.insert(key.into_owned(), i);
+        }
+    }
+
+    let mut variants = stores.iter().filter(|store| !store.index.is_empty()).map(|store| store.crc);
+    let first = variants.next().unwrap_or_default();
+    let crc = if variants.all(|crc| crc == first) { first } else { CrcVariant::Ieee };
+
+    let tmp_path = output.with_extension("merge");
+    let written = write_merged(&tmp_path, &mut stores, &owners, crc);
+    if let Err(err) = written.and_then(|()| fs::rename(&tmp_path, output)) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    Ok(owners.len())
+}
+
+fn write_merged(
+    path: &Path,
+    stores: &mut [ActionKV],
+    owners: &BTreeMap<ByteString, usize>,
+    crc: CrcVariant,
+) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(path)?);
+    let mut header = Vec::new();
+    crc.encode_header(&mut header);
+    f.write_all(&header)?;
+    for (key, &i) in owners {
+        let value = stores[i].get(key)?.ok_or(io::ErrorKind::NotFound)?;
+        write_record(&mut f, crc, key, &value)?;
+    }
+    f.flush()?;
+    f.get_ref().sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_db;
+
+    fn shards(name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let a = temp_db(&format!("{}-a", name));
+        let b = temp_db(&format!("{}-b", name));
+        let out = temp_db(&format!("{}-out", name));
+        let mut store = ActionKV::open(&a).unwrap();
+        store.insert(b"only-a", b"1").unwrap();
+        store.insert(b"shared", b"from-a").unwrap();
+        let mut store = ActionKV::open(&b).unwrap();
+        store.insert(b"only-b", b"2").unwrap();
+        store.insert(b"shared", b"from-b").unwrap();
+        (a, b, out)
+    }
+
+    fn merged_value(name: &str, conflict: ConflictPolicy) -> io::Result<ByteString> {
+        let (a, b, out) = shards(name);
+        let merged = merge_databases(&[&a, &b], &out, conflict);
+        let result = merged.map(|count| {
+            assert_eq!(count, 3);
+            let mut store = ActionKV::open(&out).unwrap();
+            store.load().unwrap();
+            assert_eq!(store.get(b"only-a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(store.get(b"only-b").unwrap(), Some(b"2".to_vec()));
+            store.get(b"shared").unwrap().unwrap()
+        });
+        for path in &[a, b, out] {
+            let _ = std::fs::remove_file(path);
+        }
+        result
+    }
+
+    #[test]
+    fn last_wins_keeps_later_input() {
+        let value = merged_value("merge-last", ConflictPolicy::LastWins).unwrap();
+        assert_eq!(value, b"from-b");
+    }
+
+    #[test]
+    fn first_wins_keeps_earlier_input() {
+        let value = merged_value("merge-first", ConflictPolicy::FirstWins).unwrap();
+        assert_eq!(value, b"from-a");
+    }
+
+    #[test]
+    fn error_policy_rejects_duplicates() {
+        let err = merged_value("merge-error", ConflictPolicy::Error).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn merging_into_an_input_keeps_its_data() {
+        let (a, b, out) = shards("merge-into-input");
+        let count = merge_databases(&[&a, &b], &a, ConflictPolicy::FirstWins).unwrap();
+        assert_eq!(count, 3);
+        let mut store = ActionKV::open(&a).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"only-a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"only-b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"shared").unwrap(), Some(b"from-a".to_vec()));
+        assert!(!a.with_extension("merge").exists());
+        assert!(!out.exists());
+        for path in &[a, b] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn merges_keep_the_checksum_variant_of_their_inputs() {
+        let (a, b) = (temp_db("merge-crc-a"), temp_db("merge-crc-b"));
+        let out = temp_db("merge-crc-out");
+        for path in &[&a, &b] {
+            let mut store = ActionKV::open(path).unwrap();
+            store.set_crc_variant(CrcVariant::Castagnoli);
+            store.insert(path.to_string_lossy().as_bytes(), b"v").unwrap();
+        }
+        // an empty input has no say
+        let empty = temp_db("merge-crc-empty");
+        ActionKV::open(&empty).unwrap();
+        merge_databases(&[&a, &empty, &b], &out, ConflictPolicy::Error).unwrap();
+        let mut merged = ActionKV::open(&out).unwrap();
+        assert_eq!(merged.crc_variant(), CrcVariant::Castagnoli);
+        merged.load().unwrap();
+        assert_eq!(merged.index.len(), 2);
+
+        // inputs that disagree get the default
+        let mut store = ActionKV::open(&empty).unwrap();
+        store.insert(b"ieee", b"v").unwrap();
+        merge_databases(&[&a, &empty, &b], &out, ConflictPolicy::Error).unwrap();
+        let mut merged = ActionKV::open(&out).unwrap();
+        assert_eq!(merged.crc_variant(), CrcVariant::Ieee);
+        merged.load().unwrap();
+        assert_eq!(merged.index.len(), 3);
+        for path in &[a, b, empty, out] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn missing_inputs_are_not_created() {
+        let (a, b, out) = shards("merge-missing");
+        let missing = temp_db("merge-missing-c");
+        let err = merge_databases(&[&a, &missing, &b], &out, ConflictPolicy::LastWins).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!missing.exists());
+        assert!(!out.exists());
+        for path in &[a, b] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use super::*;
+use crate::batch::marker_of;
+use crate::record::header_of;
+use rayon::prelude::*;
+
+// bytes of records each worker verifies at a time
+const CHUNK: usize = if cfg!(test) { 256 } else { 4 << 20 };
+
+// what a run of consecutive records did to the keys it touched
+#[derive(Default)]
+struct ChunkIndex<'a> {
+    // the last write to each key: its offset, or `None` for a tombstone
+    last: HashMap<&'a ByteStr, Option<u64>>,
+    fillers: Vec<(u64, u64)>,
+    dead: Vec<u64>,
+    // whether the chunk has markers of a `commit_batch`
+    batches: bool,
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// Builds the same index as `load`, checksumming the records on the rayon
+    /// thread pool. The file is read a batch at a time and cut into chunks of
+    /// whole records; the chunks are indexed in parallel and then merged in
+    /// file order, so a later write to a key still beats an earlier one.
+    ///
+    /// Files written with `commit_batch` are handed over to `load`, which
+    /// knows which batches were committed.
+    pub fn rebuild_index_parallel(&mut self) -> io::Result<()> {
+        self.check_file()?;
+        self.flush()?;
+        let start = self.detect_crc()?;
+        let file_len = self.f.seek(SeekFrom::End(0))?;
+        self.f.seek(SeekFrom::Start(start))?;
+        let crc = self.crc;
+
+        let batch_len = CHUNK * rayon::current_num_threads();
+        let mut target = batch_len;
+        let mut position = start;
+        // the file from `position` on, as far as it has been read
+        let mut buf = Vec::new();
+        let mut dead = Vec::new();
+        while position < file_len {
+            let want = (target as u64).min(file_len - position) as usize;
+            if buf.len() < want {
+                let more = (want - buf.len()) as u64;
+                (&mut self.f).take(more).read_to_end(&mut buf)?;
+            }
+
+            let (chunks, consumed) = split_records(&buf, position);
+            if consumed == 0 {
+                if buf.len() as u64 == file_len - position {
+                    // torn record at the end, as in `load`
+                    warn!(
+                        "ignoring a torn record of {} bytes at offset {}",
+                        buf.len(),
+                        position
+                    );
+                    break;
+                }
+                target = buf.len() * 2; // a record bigger than the batch
+                continue;
+            }
+
+            let indexed = chunks
+                .into_par_iter()
+                .map(|(base, bytes)| index_chunk(crc, base, bytes))
+                .collect::<io::Result<Vec<_>>>()?;
+            if indexed.iter().any(|chunk| chunk.batches) {
+                self.index.clear();
+                if let Some(free) = &mut self.free_list {
+                    free.clear();
+                }
+                return self.load();
+            }
+            for chunk in indexed {
+                if let Some(free) = &mut self.free_list {
+                    free.extend(chunk.fillers);
+                }
+                dead.extend(chunk.dead);
+                for (key, last) in chunk.last {
+                    let old = match last {
+                        Some(offset) => self.index.insert(key.to_vec(), offset),
+                        None => self.index.remove(key),
+                    };
+                    dead.extend(old);
+                }
+            }
+
+            buf.drain(..consumed);
+            position += consumed as u64;
+            target = batch_len;
+        }
+
+        if self.free_list.is_some() {
+            for position in dead {
+                self.free_record(position)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Cuts `buf`, which holds records from `base` on, into runs of whole records
+// of about `CHUNK` bytes each. Returns the runs with their offsets and how
+// many bytes they cover; only the headers are read here.
+fn split_records(buf: &[u8], base: u64) -> (Vec<(u64, &[u8])>, usize) {
+    let mut chunks = Vec::new();
+    let (mut start, mut end) = (0, 0);
+    while buf.len() - end >= HEADER_LEN as usize {
+        let rest = &buf[end..];
+        let (_, key_len, val_len) = match header_of(rest) {
+            Some(header) => parse_header(header),
+            None => break,
+        };
+        let record_len = HEADER_LEN as usize + key_len as usize + val_len as usize;
+        if record_len > rest.len() {
+            break;
+        }
+        end += record_len;
+        if end - start >= CHUNK {
+            chunks.push((base + start as u64, &buf[start..end]));
+            start = end;
+        }
+    }
+    if end > start {
+        chunks.push((base + start as u64, &buf[start..end]));
+    }
+    (chunks, end)
+}
+
+fn index_chunk(crc: CrcVariant, base: u64, mut bytes: &[u8]) -> io::Result<ChunkIndex<'_>> {
+    let mut chunk = ChunkIndex::default();
+    let mut position = base;
+    while !bytes.is_empty() {
+        let (key, value) = decode_record_ref(crc, bytes)?;
+        let len = HEADER_LEN + (key.len() + value.len()) as u64;
+        let checksum = header_of(bytes).map_or(0, |header| parse_header(header).0);
+        if marker_of(checksum, key, value).is_some() {
+            chunk.batches = true;
+        } else if key.is_empty() {
+            chunk.fillers.push((position, len));
+        } else if value.is_empty() {
+            if let Some(Some(old)) = chunk.last.insert(key, None) {
+                chunk.dead.push(old);
+            }
+            chunk.dead.push(position);
+        } else if let Some(Some(old)) = chunk.last.insert(key, Some(position)) {
+            chunk.dead.push(old);
+        }
+        position += len;
+        bytes = &bytes[len as usize..];
+    }
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_db;
+
+    // overwrites and deletes spread across many chunks, with fillers from
+    // the alignment and a record bigger than a whole batch
+    fn fill(store: &mut ActionKV) {
+        store.set_alignment(16);
+        for round in 0..5u8 {
+            for i in 0..200u32 {
+                if (i + round as u32).is_multiple_of(7) {
+                    store.delete(i.to_be_bytes()).unwrap();
+                } else {
+                    store.insert(i.to_be_bytes(), &[round; 9]).unwrap();
+                }
+            }
+        }
+        let huge = vec![1; CHUNK * rayon::current_num_threads() * 3];
+        store.insert(b"huge", &huge).unwrap();
+        store.insert(7u32.to_be_bytes(), b"last").unwrap();
+    }
+
+    #[test]
+    fn parallel_rebuild_matches_load() {
+        let path = temp_db("parallel");
+        fill(&mut ActionKV::open(&path).unwrap());
+
+        let mut serial = ActionKV::open(&path).unwrap();
+        serial.load().unwrap();
+        let mut parallel = ActionKV::open(&path).unwrap();
+        parallel.rebuild_index_parallel().unwrap();
+        assert_eq!(parallel.index, serial.index);
+        assert_eq!(parallel.get(7u32.to_be_bytes()).unwrap(), Some(b"last".to_vec()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parallel_rebuild_reclaims_like_load() {
+        let serial_path = temp_db("parallel-free-serial");
+        let parallel_path = temp_db("parallel-free");
+        fill(&mut ActionKV::open(&serial_path).unwrap());
+        std::fs::copy(&serial_path, &parallel_path).unwrap();
+
+        let mut serial = ActionKV::open(&serial_path).unwrap();
+        serial.set_free_list(true).unwrap();
+        serial.load().unwrap();
+        let mut parallel = ActionKV::open(&parallel_path).unwrap();
+        parallel.set_free_list(true).unwrap();
+        parallel.rebuild_index_parallel().unwrap();
+
+        assert_eq!(parallel.index, serial.index);
+        assert_eq!(parallel.free_list, serial.free_list);
+        assert_eq!(
+            std::fs::read(&parallel_path).unwrap(),
+            std::fs::read(&serial_path).unwrap()
+        );
+        std::fs::remove_file(&serial_path).unwrap();
+        std::fs::remove_file(&parallel_path).unwrap();
+    }
+}
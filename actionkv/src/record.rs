@@ -0,0 +1,383 @@
+use super::*;
+use crc::{crc32, Hasher32};
+
+// longest payload of the filler announcing a variant, see `CrcVariant::tag`
+pub(crate) const MAX_TAG_LEN: usize = 6;
+
+// The checksum field of fillers whose payload means something: the one
+// announcing a variant and the markers of a `commit_batch`. Other fillers
+// have 0 there, including freed records, which keep their old key and value
+// as payload; without this, a deleted record could pass for either.
+pub(crate) const TAGGED_FILLER: u32 = u32::from_le_bytes(*b"akvt");
+
+/// The checksum protecting the records of a file. IEEE CRC-32 is what files
+/// have always used; Castagnoli (CRC-32C) is the one many newer formats pick.
+/// `None` skips checksums altogether, for data that is cheap to lose such as
+/// caches: nothing is computed on write and nothing is verified on read.
+///
+/// Files other than IEEE start with a filler record naming the variant, so
+/// they are read back correctly; files without one are IEEE.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CrcVariant {
+    #[default]
+    Ieee,
+    Castagnoli,
+    None,
+}
+
+impl CrcVariant {
+    pub(crate) fn digest(self) -> Option<crc32::Digest> {
+        match self {
+            CrcVariant::Ieee => Some(crc32::Digest::new(crc32::IEEE)),
+            CrcVariant::Castagnoli => Some(crc32::Digest::new(crc32::CASTAGNOLI)),
+            CrcVariant::None => None,
+        }
+    }
+
+    // 0 for `None`, which never compares it
+    pub(crate) fn checksum(self, key: &ByteStr, value: &ByteStr) -> u32 {
+        match self.digest() {
+            Some(mut digest) => {
+                digest.write(key);
+                digest.write(value);
+                digest.sum32()
+            }
+            None => 0,
+        }
+    }
+
+    pub(crate) fn verifies(self) -> bool {
+        self != CrcVariant::None
+    }
+
+    // payload of the filler that announces the variant at the start of a
+    // file; IEEE goes without
+    fn tag(self) -> &'static [u8] {
+        match self {
+            CrcVariant::Ieee => b"",
+            CrcVariant::Castagnoli => b"crc32c",
+            CrcVariant::None => b"nocrc",
+        }
+    }
+
+    // appends the filler announcing this variant at the start of a file,
+    // if it needs one
+    pub(crate) fn encode_header(self, buf: &mut Vec<u8>) {
+        let tag = self.tag();
+        if !tag.is_empty() {
+            encode_tagged_filler(tag, buf);
+        }
+    }
+
+    // the variant of a file starting with `buf`, and how many bytes its
+    // announcement takes up
+    pub(crate) fn decode_header(buf: &[u8]) -> (CrcVariant, u64) {
+        for &crc in &[CrcVariant::Castagnoli, CrcVariant::None] {
+            let mut header = Vec::new();
+            crc.encode_header(&mut header);
+            if buf.starts_with(&header) {
+                return (crc, header.len() as u64);
+            }
+        }
+        (CrcVariant::Ieee, 0)
+    }
+}
+
+/// Appends the on-disk form of a record to `buf`: checksum, key length and
+/// value length as little-endian u32s, followed by key and value. The
+/// checksum is IEEE, see `encode_record_with` for the others.
+pub fn encode_record(key: &ByteStr, value: &ByteStr, buf: &mut Vec<u8>) {
+    encode_record_with(CrcVariant::Ieee, key, value, buf)
+}
+
+/// `encode_record` with the checksum computed by `crc`.
+pub fn encode_record_with(crc: CrcVariant, key: &ByteStr, value: &ByteStr, buf: &mut Vec<u8>) {
+    buf.reserve(HEADER_LEN as usize + key.len() + value.len());
+    buf.extend_from_slice(&crc.checksum(key, value).to_le_bytes());
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+}
+
+// appends a filler record carrying `payload`, marked with `TAGGED_FILLER`
+pub(crate) fn encode_tagged_filler(payload: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&TAGGED_FILLER.to_le_bytes());
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(payload);
+}
+
+// appends a filler record of `len` bytes in total, with a zeroed payload
+pub(crate) fn encode_filler(len: u64, buf: &mut Vec<u8>) {
+    debug_assert!(len >= HEADER_LEN);
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    buf.extend_from_slice(&((len - HEADER_LEN) as u32).to_le_bytes());
+    buf.resize(buf.len() + (len - HEADER_LEN) as usize, 0);
+}
+
+/// Decodes the record at the start of `buf`, ignoring any bytes after it.
+/// Filler records (empty key) carry no data worth protecting and are not
+/// checksummed.
+///
+/// Arbitrary input never panics: a short buffer is `UnexpectedEof`, a
+/// checksum mismatch `InvalidData`. The checksum is expected to be IEEE, see
+/// `decode_record_with` for the others.
+pub fn decode_record(buf: &[u8]) -> io::Result<KeyValuePair> {
+    decode_record_with(CrcVariant::Ieee, buf)
+}
+
+pub(crate) type RecordHeader = [u8; HEADER_LEN as usize];
+
+// checksum, key length and value length. On little-endian hosts these are
+// plain loads from the buffer, elsewhere `parse_header_generic` swaps them.
+pub(crate) fn parse_header(header: &RecordHeader) -> (u32, u32, u32) {
+    if cfg!(target_endian = "little") {
+        let field = |i: usize| {
+            u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]])
+        };
+        (field(0), field(4), field(8))
+    } else {
+        parse_header_generic(header)
+    }
+}
+
+fn parse_header_generic(mut header: &[u8]) -> (u32, u32, u32) {
+    let mut field = || header.read_u32::<LittleEndian>().unwrap_or_default();
+    (field(), field(), field())
+}
+
+// the header at the start of `buf`, if it is long enough to hold one
+pub(crate) fn header_of(buf: &[u8]) -> Option<&RecordHeader> {
+    buf.get(..HEADER_LEN as usize)?.try_into().ok()
+}
+
+/// `decode_record` for a record checksummed with `crc`.
+pub fn decode_record_with(crc: CrcVariant, buf: &[u8]) -> io::Result<KeyValuePair> {
+    let (key, value) = decode_record_ref(crc, buf)?;
+    let (checksum, _, _) = parse_header(header_of(buf).ok_or(io::ErrorKind::UnexpectedEof)?);
+    Ok(KeyValuePair {
+        key: key.to_vec(),
+        value: value.to_vec(),
+        checksum,
+        crc,
+        redacted: false,
+    })
+}
+
+// `decode_record` without copying: key and value borrowed from `buf`
+pub(crate) fn decode_record_ref(crc: CrcVariant, buf: &[u8]) -> io::Result<(&[u8], &[u8])> {
+    let header = header_of(buf).ok_or(io::ErrorKind::UnexpectedEof)?;
+    let (saved_checksum, key_len, val_len) = parse_header(header);
+    let (key_len, val_len) = (key_len as usize, val_len as usize);
+
+    let data = &buf[HEADER_LEN as usize..];
+    match key_len.checked_add(val_len) {
+        Some(data_len) if data_len <= data.len() => {}
+        _ => return Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+    let key = &data[..key_len];
+    let value = &data[key_len..key_len + val_len];
+
+    let checksum = crc.checksum(key, value);
+    if key_len != 0 && crc.verifies() && checksum != saved_checksum {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "data corruption encountered ({:08x} != {:08x})",
+                checksum, saved_checksum
+            ),
+        ));
+    }
+    Ok((key, value))
+}
+
+pub(crate) fn write_record<W: Write>(
+    f: &mut W,
+    crc: CrcVariant,
+    key: &ByteStr,
+    value: &ByteStr,
+) -> io::Result<()> {
+    let mut buf = Vec::new();
+    encode_record_with(crc, key, value, &mut buf);
+    f.write_all(&buf)
+}
+
+/// Reads the next record from `f`, or `None` if `f` is at its end. Only a
+/// clean end, right after a whole record, counts: a record cut short is
+/// `UnexpectedEof`, and a checksum mismatch `InvalidData`, as in
+/// `decode_record`. The checksum is expected to be IEEE, see
+/// `read_record_with` for the others.
+pub fn read_record<R: Read>(f: &mut R) -> io::Result<Option<KeyValuePair>> {
+    read_record_with(CrcVariant::Ieee, f)
+}
+
+/// `read_record` for a record checksummed with `crc`.
+pub fn read_record_with<R: Read>(crc: CrcVariant, f: &mut R) -> io::Result<Option<KeyValuePair>> {
+    let mut header = [0; HEADER_LEN as usize];
+    let mut filled = 0;
+    while filled < header.len() {
+        match f.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    let (_, key_len, val_len) = parse_header(&header);
+    let data_len = key_len as u64 + val_len as u64;
+    let mut buf = header.to_vec();
+    f.by_ref().take(data_len).read_to_end(&mut buf)?;
+    // a record cut short by a crash is reported as UnexpectedEof; the data is
+    // read as it arrives, so a bogus length can't force a huge allocation
+    decode_record_with(crc, &buf).map(Some)
+}
+
+// `read_record_with` for a record that has to be there
+pub(crate) fn process_record<R: Read>(f: &mut R, crc: CrcVariant) -> io::Result<KeyValuePair> {
+    read_record_with(crc, f)?.ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn encode_then_decode_round_trips() {
+        let mut buf = Vec::new();
+        encode_record(b"key", b"value", &mut buf);
+        assert_eq!(buf.len(), HEADER_LEN as usize + 8);
+
+        let kv = decode_record(&buf).unwrap();
+        assert_eq!(kv.key, b"key");
+        assert_eq!(kv.value, b"value");
+        assert!(kv.verify());
+    }
+
+    #[test]
+    fn decode_rejects_truncated_buffers() {
+        let mut buf = Vec::new();
+        encode_record(b"key", b"value", &mut buf);
+        for len in 0..buf.len() {
+            let err = decode_record(&buf[..len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn reading_past_the_last_record_is_none() {
+        let mut buf = Vec::new();
+        encode_record(b"a", b"1", &mut buf);
+        encode_record(b"b", b"2", &mut buf);
+
+        let mut f = buf.as_slice();
+        assert_eq!(read_record(&mut f).unwrap().unwrap().key, b"a");
+        assert_eq!(read_record(&mut f).unwrap().unwrap().key, b"b");
+        assert!(read_record(&mut f).unwrap().is_none());
+
+        // only part of a record is an error, wherever it is cut
+        for len in 1..buf.len() / 2 {
+            let err = read_record(&mut &buf[..len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn decode_rejects_bad_checksums() {
+        let mut buf = Vec::new();
+        encode_record(b"key", b"value", &mut buf);
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        let err = decode_record(&buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = process_record(&mut buf.as_slice(), CrcVariant::Ieee).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn fast_and_generic_header_parsing_agree() {
+        let mut buf = Vec::new();
+        encode_record(b"key", &[9; 300], &mut buf);
+        let mut header = *header_of(&buf).unwrap();
+        assert_eq!(parse_header(&header), (checksum_of(&buf), 3, 300));
+        for seed in 0..=255u8 {
+            for (i, byte) in header.iter_mut().enumerate() {
+                *byte = seed.wrapping_mul(31).wrapping_add(i as u8 * 17);
+            }
+            assert_eq!(parse_header(&header), parse_header_generic(&header));
+        }
+    }
+
+    fn checksum_of(buf: &[u8]) -> u32 {
+        (&buf[..4]).read_u32::<LittleEndian>().unwrap()
+    }
+
+    // cargo test --release -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn header_parsing_speed() {
+        use std::hint::black_box;
+        use std::time::Instant;
+
+        let mut buf = Vec::new();
+        encode_record(b"key", b"value", &mut buf);
+        let header = *header_of(&buf).unwrap();
+        let rounds = 50_000_000;
+
+        let started = Instant::now();
+        for _ in 0..rounds {
+            black_box(parse_header(black_box(&header)));
+        }
+        let fast = started.elapsed();
+        let started = Instant::now();
+        for _ in 0..rounds {
+            black_box(parse_header_generic(black_box(&header)));
+        }
+        let generic = started.elapsed();
+        println!("{} headers: fast {:?}, generic {:?}", rounds, fast, generic);
+    }
+
+    #[test]
+    fn each_crc_variant_round_trips_and_rejects_the_other() {
+        for &(crc, other) in &[
+            (CrcVariant::Ieee, CrcVariant::Castagnoli),
+            (CrcVariant::Castagnoli, CrcVariant::Ieee),
+        ] {
+            let mut buf = Vec::new();
+            encode_record_with(crc, b"key", b"value", &mut buf);
+            let kv = decode_record_with(crc, &buf).unwrap();
+            assert_eq!((kv.key.as_slice(), kv.value.as_slice()), (&b"key"[..], &b"value"[..]));
+            assert!(kv.verify());
+
+            let err = decode_record_with(other, &buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn decode_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let _ = decode_record(&bytes);
+            let _ = process_record(&mut bytes.as_slice(), CrcVariant::Ieee);
+        }
+
+        #[test]
+        fn decode_never_panics_on_damaged_records(
+            key in proptest::collection::vec(any::<u8>(), 1..32),
+            value in proptest::collection::vec(any::<u8>(), 0..64),
+            flip in any::<usize>(),
+            cut in any::<usize>(),
+        ) {
+            let mut buf = Vec::new();
+            encode_record(&key, &value, &mut buf);
+            let i = flip % buf.len();
+            buf[i] = buf[i].wrapping_add(1);
+            buf.truncate(cut % (buf.len() + 1));
+            let _ = decode_record(&buf);
+            let _ = process_record(&mut buf.as_slice(), CrcVariant::Ieee);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use super::*;
+
+impl ActionKV {
+    /// Have `load_recover` append the raw bytes of every corrupt record it
+    /// skips to `quarantine_path`, for looking into later.
+    pub fn set_quarantine(&mut self, enabled: bool) {
+        self.quarantine = enabled;
+    }
+
+    /// Where corrupt records are kept: the data file's path with a
+    /// `.quarantine` extension.
+    pub fn quarantine_path(&self) -> io::Result<PathBuf> {
+        Ok(self.path()?.with_extension("quarantine"))
+    }
+
+    /// Like `load`, but skips records that fail their checksum instead of
+    /// giving up on the whole file, and returns how many were skipped. Their
+    /// offsets are logged, and with `set_quarantine` the records themselves
+    /// are saved.
+    pub fn load_recover(&mut self) -> io::Result<usize> {
+        let corrupt = self.load_records(|_, _| {}, true)?;
+        if self.quarantine && !corrupt.is_empty() {
+            let path = self.quarantine_path()?;
+            let mut out = OpenOptions::new().create(true).append(true).open(&path)?;
+            for (position, raw) in &corrupt {
+                out.write_all(raw)?;
+                warn!(
+                    "quarantined {} bytes from offset {} in {}",
+                    raw.len(),
+                    position,
+                    path.display()
+                );
+            }
+            out.sync_all()?;
+        }
+        Ok(corrupt.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_db;
+
+    #[test]
+    fn corrupt_records_are_skipped_and_quarantined() {
+        let path = temp_db("quarantine");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert("a", b"1").unwrap();
+        store.insert("b", b"2").unwrap();
+        store.insert("c", b"3").unwrap();
+        let position = store.index[&b"b".to_vec()] as usize;
+        drop(store);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[position + HEADER_LEN as usize + 1] = b'X';
+        std::fs::write(&path, &bytes).unwrap();
+        let record = &bytes[position..position + HEADER_LEN as usize + 2];
+
+        let mut store = ActionKV::open(&path).unwrap();
+        assert_eq!(store.load().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        store.index.clear();
+        store.set_quarantine(true);
+        assert_eq!(store.load_recover().unwrap(), 1);
+        assert_eq!(store.get("a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get("b").unwrap(), None);
+        assert_eq!(store.get("c").unwrap(), Some(b"3".to_vec()));
+
+        let quarantine = store.quarantine_path().unwrap();
+        assert_eq!(std::fs::read(&quarantine).unwrap(), record);
+        std::fs::remove_file(&quarantine).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
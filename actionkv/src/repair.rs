@@ -0,0 +1,172 @@
+use super::*;
+
+/// What `verify_and_repair` kept and dropped, in records.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// The newest valid record of every live key, written to the repaired file.
+    pub kept: usize,
+    /// Records overwritten or deleted by a later write to the same key.
+    pub stale: usize,
+    /// Tombstones, which the repaired file doesn't need.
+    pub tombstones: usize,
+    /// Records that failed their checksum.
+    pub corrupt: usize,
+    /// Records of batches that were never committed.
+    pub uncommitted: usize,
+    /// Bytes of a record torn off at the end of the file.
+    pub torn_bytes: u64,
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// Scans the whole file and writes a clean, compacted copy to `dest`:
+    /// the newest valid record of every key that isn't deleted, in file
+    /// order. Corrupt records are skipped, so a key whose latest write is
+    /// damaged keeps the write before it. Neither the file nor the index of
+    /// this store is changed; open `dest` to use the repaired data.
+    pub fn verify_and_repair(&mut self, dest: &Path) -> io::Result<RepairReport> {
+        self.check_file()?;
+        self.flush()?;
+        let start = self.detect_crc()?;
+        let file_len = self.f.seek(SeekFrom::End(0))?;
+        let crc = self.crc;
+
+        let mut report = RepairReport::default();
+        // the newest valid write to each key, `None` for a tombstone
+        let mut newest: HashMap<ByteString, Option<u64>> = HashMap::new();
+        let mut batch: Option<OpenBatch> = None;
+        {
+            let mut f = BufReader::new(&mut self.f);
+            f.seek(SeekFrom::Start(start))?;
+            loop {
+                let position = f.stream_position()?;
+                let kv = match read_record_with(crc, &mut f) {
+                    Ok(Some(kv)) => kv,
+                    Ok(None) => break,
+                    Err(err) => match err.kind() {
+                        io::ErrorKind::UnexpectedEof => {
+                            report.torn_bytes = file_len - position;
+                            break;
+                        }
+                        io::ErrorKind::InvalidData => {
+                            warn!("dropping a corrupt record at offset {}", position);
+                            report.corrupt += 1;
+                            f.seek(SeekFrom::Start(position))?;
+                            let mut header = [0; HEADER_LEN as usize];
+                            f.read_exact(&mut header)?;
+                            let (_, key_len, val_len) = parse_header(&header);
+                            f.seek_relative(key_len as i64 + val_len as i64)?;
+                            continue;
+                        }
+                        _ => return Err(err),
+                    },
+                };
+
+                let mut records = Vec::new();
+                if let Some(open) = &mut batch {
+                    if position < open.end {
+                        open.records.push((kv, position));
+                        continue;
+                    }
+                }
+                let marker = marker_of(kv.checksum, &kv.key, &kv.value);
+                if let Some(open) = batch.take() {
+                    if position == open.end && marker == Some(Marker::Commit) {
+                        records = open.records;
+                    } else {
+                        report.uncommitted += open.records.len();
+                    }
+                }
+                match marker {
+                    Some(Marker::Begin(len)) => batch = Some(OpenBatch::new(position, len)),
+                    Some(Marker::Commit) => {}
+                    None => records.push((kv, position)),
+                }
+
+                for (kv, position) in records {
+                    if kv.key.is_empty() {
+                        continue; // filler
+                    }
+                    let live = Some(position).filter(|_| !kv.value.is_empty());
+                    if live.is_none() {
+                        report.tombstones += 1;
+                    }
+                    if let Some(Some(_)) = newest.insert(kv.key, live) {
+                        report.stale += 1;
+                    }
+                }
+            }
+        }
+        if let Some(open) = batch {
+            report.uncommitted += open.records.len();
+        }
+
+        let mut live: Vec<u64> = newest.values().flatten().cloned().collect();
+        live.sort_unstable();
+        let mut out = BufWriter::new(File::create(dest)?);
+        let mut header = Vec::new();
+        crc.encode_header(&mut header);
+        out.write_all(&header)?;
+        for position in live {
+            let kv = self.get_at(position)?;
+            write_record(&mut out, crc, &kv.key, &kv.value)?;
+            report.kept += 1;
+        }
+        out.flush()?;
+        out.get_ref().sync_all()?;
+        debug!("repaired into {}: {:?}", dest.display(), report);
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_db;
+
+    #[test]
+    fn repair_keeps_only_the_newest_valid_records() {
+        let path = temp_db("repair");
+        let dest = temp_db("repair-out");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"old").unwrap();
+        store.insert(b"a", b"new").unwrap();
+        store.insert(b"b", b"gone").unwrap();
+        store.delete(b"b").unwrap();
+        store.insert(b"c", b"good").unwrap();
+        store.insert(b"c", b"damaged").unwrap();
+        let damaged = store.index[&b"c".to_vec()] as usize;
+        store.commit_batch(&[(b"d".to_vec(), b"batched".to_vec())]).unwrap();
+        store.insert(b"e", b"torn").unwrap();
+        drop(store);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[damaged + HEADER_LEN as usize + 2] ^= 0xff;
+        bytes.truncate(bytes.len() - 2);
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        let report = store.verify_and_repair(&dest).unwrap();
+        assert_eq!(
+            report,
+            RepairReport {
+                kept: 3,
+                stale: 2,
+                tombstones: 1,
+                corrupt: 1,
+                uncommitted: 0,
+                torn_bytes: HEADER_LEN + 1 + 4 - 2,
+            }
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+        let mut repaired = ActionKV::open(&dest).unwrap();
+        repaired.load().unwrap();
+        assert_eq!(repaired.index.len(), 3);
+        assert_eq!(repaired.get(b"a").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(repaired.get(b"b").unwrap(), None);
+        assert_eq!(repaired.get(b"c").unwrap(), Some(b"good".to_vec()));
+        assert_eq!(repaired.get(b"d").unwrap(), Some(b"batched".to_vec()));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&dest).unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+use super::*;
+
+impl<S: Storage> ActionKV<S> {
+    /// Applies a stream of records shipped from a leader, e.g. the bytes it
+    /// appended to its data file since the last call, and returns the number
+    /// of bytes consumed. Every record is checked against its checksum
+    /// before it is written; the first one that fails, or is cut short,
+    /// stops the stream with `InvalidData` or `UnexpectedEof` naming its
+    /// offset in the stream. The records before it stay applied, so a
+    /// resync can resume from that offset.
+    ///
+    /// Leader and follower have to use the same `CrcVariant`. Fillers are
+    /// skipped, and the records of a leader's `commit_batch` are applied one
+    /// by one.
+    pub fn apply_replication<R: Read>(&mut self, stream: R) -> io::Result<u64> {
+        let mut r = BufReader::new(stream);
+        let mut offset = 0;
+        loop {
+            let kv = match read_record_with(self.crc, &mut r) {
+                Ok(Some(kv)) => kv,
+                Ok(None) => break,
+                Err(err) => {
+                    return Err(io::Error::new(
+                        err.kind(),
+                        format!("bad record at offset {} of the replication stream: {}", offset, err),
+                    ))
+                }
+            };
+            if kv.key.is_empty() {
+                // filler
+            } else if kv.value.is_empty() {
+                self.delete(&kv.key)?;
+            } else {
+                self.insert(&kv.key, &kv.value)?;
+            }
+            offset += HEADER_LEN + (kv.key.len() + kv.value.len()) as u64;
+        }
+        self.flush()?;
+        Ok(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn corrupt_frames_stop_the_stream_at_their_offset() {
+        let mut leader = ActionKV::with_storage(Cursor::new(Vec::new()));
+        leader.insert("a", b"1").unwrap();
+        leader.insert("b", b"2").unwrap();
+        leader.delete("a").unwrap();
+        let bad = leader.seek_to_end().unwrap();
+        leader.insert("c", b"3").unwrap();
+        leader.insert("d", b"4").unwrap();
+        leader.flush().unwrap();
+        let mut stream = leader.f.get_ref().clone();
+
+        let mut follower = ActionKV::with_storage(Cursor::new(Vec::new()));
+        assert_eq!(follower.apply_replication(&stream[..]).unwrap(), stream.len() as u64);
+        assert_eq!(follower.index.len(), 3);
+
+        stream[bad as usize + HEADER_LEN as usize] ^= 0xff;
+        let mut follower = ActionKV::with_storage(Cursor::new(Vec::new()));
+        let err = follower.apply_replication(&stream[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(&format!("offset {}", bad)));
+        assert_eq!(follower.get("a").unwrap(), None);
+        assert_eq!(follower.get("b").unwrap(), Some(b"2".to_vec()));
+        assert!(!follower.contains_key("c") && !follower.contains_key("d"));
+
+        // resync from the bad record on
+        stream[bad as usize + HEADER_LEN as usize] ^= 0xff;
+        follower.apply_replication(&stream[bad as usize..]).unwrap();
+        assert_eq!(follower.get("d").unwrap(), Some(b"4".to_vec()));
+
+        let err = follower.apply_replication(&stream[..stream.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,554 @@
+use super::*;
+use crc::{crc32, Hasher32};
+
+// bytes of the data file checksummed at a time
+const CHUNK_LEN: usize = 64 * 1024;
+
+// changes the delta log takes before the whole index is saved again
+const LOG_SNAPSHOT_EVERY: usize = if cfg!(test) { 8 } else { 4096 };
+
+/// How `save_index` writes the index next to the data file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndexFormat {
+    /// Compact and quick to read back.
+    #[default]
+    Bincode,
+    /// Readable, for inspecting the index by hand.
+    Json,
+    /// Compact and understood by MessagePack libraries in other languages.
+    MessagePack,
+}
+
+// what goes into the sidecar; the data file's length at the time, and a
+// checksum of all of it, tell a stale index apart from a current one, even
+// after records were written in place
+#[derive(Serialize, Deserialize)]
+struct SavedIndex {
+    file_len: u64,
+    data_crc: u32,
+    entries: Vec<(ByteString, u64)>,
+}
+
+/// The delta log kept by `set_index_log`.
+#[derive(Debug)]
+pub(crate) struct IndexLog {
+    f: File,
+    // entries since the index was last saved
+    entries: usize,
+}
+
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+impl ActionKV {
+    /// Opens `path` and gets its index the quickest way that's safe: from the
+    /// sidecar and its delta log if they match the data file, otherwise by
+    /// scanning the file, after which the sidecar is written afresh for next
+    /// time.
+    pub fn open_fast(path: &Path) -> io::Result<Self> {
+        let mut store = ActionKV::open(path)?;
+        match store.load_index_log() {
+            Ok(()) => return Ok(store),
+            Err(err) => match err.kind() {
+                io::ErrorKind::NotFound
+                | io::ErrorKind::InvalidData
+                | io::ErrorKind::UnexpectedEof => {
+                    debug!("scanning {}, the saved index is unusable: {}", path.display(), err)
+                }
+                _ => return Err(err),
+            },
+        }
+        store.index.clear();
+        store.load()?;
+        if let Err(err) = store.save_index() {
+            warn!("couldn't save the index of {}: {}", path.display(), err);
+        }
+        Ok(store)
+    }
+
+    /// Closes the store for good: writes out buffered records, syncs the
+    /// data file to disk and saves the index, so the next open can use
+    /// `load_index` instead of scanning the file.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        self.flush()?;
+        self.f.sync()?;
+        self.save_index()
+    }
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// The format used by `save_index` and expected by `load_index`.
+    pub fn set_index_format(&mut self, format: IndexFormat) {
+        self.index_format = format;
+    }
+
+    /// Where `save_index` puts the index: the data file's path with an
+    /// `.index` extension.
+    pub fn index_path(&self) -> io::Result<PathBuf> {
+        Ok(self.path()?.with_extension("index"))
+    }
+
+    /// Where `set_index_log` records index changes: the data file's path
+    /// with an `.indexlog` extension.
+    pub fn index_log_path(&self) -> io::Result<PathBuf> {
+        Ok(self.path()?.with_extension("indexlog"))
+    }
+
+    /// Keeps the sidecar current as the store changes. Enabling saves the
+    /// index, then every insert and delete appends the key's new offset to
+    /// a log next to it, which `load_index_log` replays on top of the saved
+    /// index; after every few thousand changes the index is saved again and
+    /// the log started over. A crash loses at most a torn last entry, after
+    /// which the log no longer matches the data file and `open_fast` falls
+    /// back to scanning it.
+    pub fn set_index_log(&mut self, enabled: bool) -> io::Result<()> {
+        if !enabled {
+            self.index_log = None;
+            return Ok(());
+        }
+        let f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.index_log_path()?)?;
+        self.index_log = Some(IndexLog { f, entries: 0 });
+        self.save_index()
+    }
+
+    /// Writes the index to `index_path`, so the next `load_index` can skip
+    /// scanning the data file. It goes to a temporary file first, which is
+    /// synced and renamed over the old index, so a crash leaves one or the
+    /// other. The delta log, which the new index makes redundant, is emptied.
+    pub fn save_index(&mut self) -> io::Result<()> {
+        let file_len = self.seek_to_end()?;
+        let mut entries: Vec<(ByteString, u64)> =
+            self.index.iter().map(|(k, v)| (k.into_owned(), v)).collect();
+        entries.sort_unstable();
+        let data_crc = self.data_crc(file_len)?;
+        let saved = SavedIndex {
+            file_len,
+            data_crc,
+            entries,
+        };
+
+        let index_path = self.index_path()?;
+        let tmp_path = index_path.with_extension("index-tmp");
+        let written = write_saved_index(&tmp_path, &saved, self.index_format);
+        if let Err(err) = written.and_then(|()| fs::rename(&tmp_path, &index_path)) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err);
+        }
+
+        match &mut self.index_log {
+            Some(log) => {
+                log.f.set_len(0)?;
+                log.entries = 0;
+            }
+            // left over from a store that had the log enabled
+            None => match fs::remove_file(self.index_log_path()?) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+
+    /// Replaces the index with the one written by `save_index`. Fails with
+    /// `InvalidData` if the data file has changed since, in length or in
+    /// place, in which case `load` has to rebuild it. Telling takes reading
+    /// the whole file once, which is still much quicker than parsing it.
+    pub fn load_index(&mut self) -> io::Result<()> {
+        let file_len = self.seek_to_end()?;
+        let saved = self.read_saved_index()?;
+        if saved.file_len != file_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "index is for {} bytes of data, the file has {}",
+                    saved.file_len, file_len
+                ),
+            ));
+        }
+        self.check_data(&saved)?;
+        self.index.clear();
+        self.index.extend(saved.entries);
+        Ok(())
+    }
+
+    /// Like `load_index`, but also replays the changes `set_index_log`
+    /// recorded since the index was saved. Fails with `InvalidData` unless
+    /// the log accounts for all of the data file.
+    pub fn load_index_log(&mut self) -> io::Result<()> {
+        let file_len = self.seek_to_end()?;
+        let saved = self.read_saved_index()?;
+        self.check_data(&saved)?;
+        // kept the way the store was set up to keep it
+        let mut index = self.index.empty_like(saved.entries.len());
+        index.extend(saved.entries);
+
+        let mut end = saved.file_len;
+        let log = match File::open(self.index_log_path()?) {
+            Ok(f) => Some(f),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        if let Some(f) = log {
+            let mut r = BufReader::new(f);
+            loop {
+                let (key, position, logged_end) = match read_log_entry(&mut r) {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => break,
+                    // torn by a crash, and what it was about is lost
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(err) => return Err(err),
+                };
+                match position {
+                    Some(position) => index.insert(key, position),
+                    None => index.remove(&key),
+                };
+                end = logged_end;
+            }
+        }
+        if end != file_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "index and log are for {} bytes of data, the file has {}",
+                    end, file_len
+                ),
+            ));
+        }
+        self.index = index;
+        Ok(())
+    }
+
+    fn read_saved_index(&mut self) -> io::Result<SavedIndex> {
+        let r = BufReader::new(File::open(self.index_path()?)?);
+        Ok(match self.index_format {
+            IndexFormat::Bincode => bincode::deserialize_from(r).map_err(invalid_data)?,
+            IndexFormat::Json => serde_json::from_reader(r)?,
+            IndexFormat::MessagePack => rmp_serde::decode::from_read(r).map_err(invalid_data)?,
+        })
+    }
+
+    // Guards against a data file that changed without the sidecar knowing:
+    // replaced by one of the same length, or written in place by a free
+    // slot, an overwrite or another process.
+    fn check_data(&mut self, saved: &SavedIndex) -> io::Result<()> {
+        if saved.data_crc != self.data_crc(saved.file_len)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "index is for different data of the same length",
+            ));
+        }
+        Ok(())
+    }
+
+    // checksum of the first `file_len` bytes of the data file
+    fn data_crc(&mut self, file_len: u64) -> io::Result<u32> {
+        self.f.seek(SeekFrom::Start(0))?;
+        let mut digest = crc32::Digest::new(crc32::IEEE);
+        let mut data = (&mut self.f).take(file_len);
+        let mut chunk = vec![0; CHUNK_LEN];
+        loop {
+            match data.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => digest.write(&chunk[..n]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(digest.sum32())
+    }
+
+    // Appends the new offset of `key`, or `None` once it's deleted, to the
+    // delta log if there is one.
+    pub(crate) fn log_index(&mut self, key: &ByteStr, position: Option<u64>) -> io::Result<()> {
+        if self.index_log.is_none() {
+            return Ok(());
+        }
+        let end = self.f.seek(SeekFrom::End(0))? + self.pending.len() as u64;
+        self.log_index_at(key, position, end)
+    }
+
+    // Like `log_index`, but with the end of the data the entry accounts for
+    // given, for changes logged in several entries: all but the last claim
+    // the end from before, so the log only matches once the last is in.
+    pub(crate) fn log_index_at(
+        &mut self,
+        key: &ByteStr,
+        position: Option<u64>,
+        end: u64,
+    ) -> io::Result<()> {
+        let log = match &mut self.index_log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let mut buf = Vec::with_capacity(4 + key.len() + 16);
+        buf.write_u32::<LittleEndian>(key.len() as u32)?;
+        buf.extend_from_slice(key);
+        buf.write_u64::<LittleEndian>(position.unwrap_or(u64::MAX))?;
+        buf.write_u64::<LittleEndian>(end)?;
+        // one write, so a crash can only tear the last entry
+        log.f.write_all(&buf)?;
+        log.entries += 1;
+        if log.entries >= LOG_SNAPSHOT_EVERY {
+            self.save_index()?;
+        }
+        Ok(())
+    }
+}
+
+fn write_saved_index(path: &Path, saved: &SavedIndex, format: IndexFormat) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    match format {
+        IndexFormat::Bincode => bincode::serialize_into(&mut w, saved).map_err(invalid_data)?,
+        IndexFormat::Json => serde_json::to_writer(&mut w, saved)?,
+        IndexFormat::MessagePack => rmp_serde::encode::write(&mut w, saved).map_err(invalid_data)?,
+    }
+    w.into_inner().map_err(|err| err.into_error())?.sync_all()
+}
+
+// [key_len u32][key][position u64, or u64::MAX once deleted][end of data u64]
+fn read_log_entry<R: Read>(r: &mut R) -> io::Result<Option<(ByteString, Option<u64>, u64)>> {
+    let key_len = match r.read_u32::<LittleEndian>() {
+        Ok(len) => len,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut key = vec![0; key_len as usize];
+    r.read_exact(&mut key)?;
+    let position = r.read_u64::<LittleEndian>()?;
+    let end = r.read_u64::<LittleEndian>()?;
+    Ok(Some((key, Some(position).filter(|&p| p != u64::MAX), end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{file_len, temp_db};
+
+    #[test]
+    fn every_index_format_round_trips() {
+        for &format in &[IndexFormat::Bincode, IndexFormat::Json, IndexFormat::MessagePack] {
+            let path = temp_db(&format!("sidecar-{:?}", format));
+            let mut store = ActionKV::open(&path).unwrap();
+            store.set_index_format(format);
+            store.insert(b"a", b"1").unwrap();
+            store.insert(b"a", b"2").unwrap();
+            store.insert(&[0, 255, 7][..], b"binary").unwrap();
+            store.save_index().unwrap();
+
+            let mut restored = ActionKV::open(&path).unwrap();
+            restored.set_index_format(format);
+            restored.load_index().unwrap();
+            assert_eq!(restored.index, store.index);
+            assert_eq!(restored.get(b"a").unwrap(), Some(b"2".to_vec()));
+
+            store.insert(b"b", b"3").unwrap();
+            let err = restored.load_index().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+            std::fs::remove_file(store.index_path().unwrap()).unwrap();
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn open_fast_uses_a_current_sidecar_and_rebuilds_a_stale_one() {
+        let path = temp_db("open-fast");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        let index_path = store.index_path().unwrap();
+
+        // missing: scanned, and saved for next time
+        let fast = ActionKV::open_fast(&path).unwrap();
+        assert_eq!(fast.index, store.index);
+        assert!(index_path.exists());
+
+        // current: taken as saved, even when it disagrees with the file
+        let mut planted = ActionKV::open(&path).unwrap();
+        planted.index.insert(b"planted".to_vec(), 0);
+        planted.save_index().unwrap();
+        let fast = ActionKV::open_fast(&path).unwrap();
+        assert!(fast.contains_key(b"planted"));
+
+        // stale: the same length, but different data at the end
+        std::fs::remove_file(&path).unwrap();
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"3").unwrap();
+        let mut fast = ActionKV::open_fast(&path).unwrap();
+        assert!(!fast.contains_key(b"planted"));
+        assert_eq!(fast.get(b"b").unwrap(), Some(b"3".to_vec()));
+        let mut reopened = ActionKV::open(&path).unwrap();
+        reopened.load_index().unwrap();
+        assert_eq!(reopened.index, fast.index);
+
+        std::fs::remove_file(&index_path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_in_place_make_the_index_stale() {
+        let path = temp_db("sidecar-in-place");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_free_list(true).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        // far more than one page behind the first record
+        store.insert(b"big", &[7; 10_000][..]).unwrap();
+        store.save_index().unwrap();
+        let index_path = store.index_path().unwrap();
+        assert!(!index_path.with_extension("index-tmp").exists());
+
+        // overwritten at the start of the file, the same length as before
+        store.set_overwrite_in_place(true);
+        store.insert(b"a", b"3").unwrap();
+        let err = ActionKV::open(&path).unwrap().load_index().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // a filler where `b` was, and nothing appended
+        store.save_index().unwrap();
+        let len = file_len(&path);
+        store.delete(b"b").unwrap();
+        assert_eq!(file_len(&path), len);
+        let err = ActionKV::open(&path).unwrap().load_index().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut fast = ActionKV::open_fast(&path).unwrap();
+        assert!(!fast.contains_key(b"b"));
+        assert_eq!(fast.get(b"a").unwrap(), Some(b"3".to_vec()));
+
+        std::fs::remove_file(&index_path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn shutdown_leaves_an_index_to_reopen_with() {
+        let path = temp_db("shutdown");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_flush_every_n(100);
+        for i in 0..50u64 {
+            store.insert_u64(i, i * 2).unwrap();
+        }
+        let index_path = store.index_path().unwrap();
+        store.shutdown().unwrap();
+
+        let mut reopened = ActionKV::open(&path).unwrap();
+        reopened.load_index().unwrap();
+        assert_eq!(reopened.index.len(), 50);
+        for i in 0..50u64 {
+            assert_eq!(reopened.get_u64(i).unwrap(), Some(i * 2));
+        }
+        std::fs::remove_file(&index_path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn index_log_rebuilds_the_index_after_a_crash() {
+        let path = temp_db("index-log");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"before", b"0").unwrap();
+        store.set_index_log(true).unwrap();
+        let log_path = store.index_log_path().unwrap();
+        // fewer changes than a snapshot takes, then more
+        for (round, changes) in [(0u64, 5u64), (1, 2 * LOG_SNAPSHOT_EVERY as u64 + 3)] {
+            for i in 0..changes {
+                if i % 4 == 3 {
+                    store.delete(i.to_be_bytes()).unwrap();
+                } else {
+                    store.insert_u64(i, i + round).unwrap();
+                }
+            }
+            store
+                .commit_batch(&[(b"batched".to_vec(), b"v".to_vec()), (b"before".to_vec(), vec![])])
+                .unwrap();
+            assert!(file_len(&log_path) > 0);
+
+            // no shutdown, just what made it to disk
+            let mut scanned = ActionKV::open(&path).unwrap();
+            scanned.load().unwrap();
+            let mut replayed = ActionKV::open(&path).unwrap();
+            replayed.load_index_log().unwrap();
+            assert_eq!(replayed.index, scanned.index);
+            assert_eq!(replayed.index, store.index);
+            let fast = ActionKV::open_fast(&path).unwrap();
+            assert_eq!(fast.index, store.index);
+        }
+
+        // a torn last entry leaves the log short of the data file
+        let len = file_len(&log_path);
+        let f = OpenOptions::new().write(true).open(&log_path).unwrap();
+        f.set_len(len - 3).unwrap();
+        let mut replayed = ActionKV::open(&path).unwrap();
+        let err = replayed.load_index_log().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let fast = ActionKV::open_fast(&path).unwrap();
+        assert_eq!(fast.index, store.index);
+        assert!(!log_path.exists());
+
+        std::fs::remove_file(store.index_path().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn the_log_keeps_the_configured_hasher() {
+        let path = temp_db("index-log-hasher");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_index_log(true).unwrap();
+        store.insert(b"a", b"1").unwrap();
+
+        let mut replayed = ActionKV::open(&path).unwrap();
+        replayed.set_deterministic_hashing(true);
+        replayed.load_index_log().unwrap();
+        assert_eq!(replayed.index, store.index);
+        assert!(matches!(replayed.index.hasher(), IndexHasher::Deterministic));
+
+        std::fs::remove_file(store.index_log_path().unwrap()).unwrap();
+        std::fs::remove_file(store.index_path().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_without_the_log_make_it_stale() {
+        let path = temp_db("index-log-stale");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_index_log(true).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load_index_log().unwrap();
+        store.insert(b"b", b"2").unwrap();
+        let err = ActionKV::open(&path).unwrap().load_index_log().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(store.index_log_path().unwrap()).unwrap();
+        std::fs::remove_file(store.index_path().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn the_log_is_folded_into_the_index_every_so_often() {
+        let path = temp_db("index-log-snapshot");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.set_index_log(true).unwrap();
+        let log_path = store.index_log_path().unwrap();
+        for i in 0..LOG_SNAPSHOT_EVERY as u64 - 1 {
+            store.insert_u64(i, i).unwrap();
+        }
+        assert!(file_len(&log_path) > 0);
+        store.insert(b"last", b"v").unwrap();
+        assert_eq!(file_len(&log_path), 0);
+
+        // the saved index alone is now current
+        let mut restored = ActionKV::open(&path).unwrap();
+        restored.load_index().unwrap();
+        assert_eq!(restored.index, store.index);
+
+        std::fs::remove_file(&log_path).unwrap();
+        std::fs::remove_file(store.index_path().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
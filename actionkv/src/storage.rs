@@ -0,0 +1,56 @@
+use super::*;
+use std::io::Cursor;
+
+/// What an `ActionKV` keeps its records in. Besides reading, writing and
+/// seeking, the store needs to cut the data short and to make writes durable.
+pub trait Storage: Read + Write + Seek {
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// The whole contents, if they are already in memory. Lets reads hand out
+    /// borrowed slices instead of copies.
+    fn as_bytes(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+impl Storage for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+impl Storage for Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> {
+        Some(self.get_ref())
+    }
+}
+
+impl Storage for Cursor<&mut Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> {
+        Some(self.get_ref())
+    }
+}
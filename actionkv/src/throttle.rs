@@ -0,0 +1,110 @@
+use super::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What `insert` does when it runs into the limit set by `set_write_limit`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThrottleMode {
+    /// Wait until the write fits into the budget again.
+    Sleep,
+    /// Fail with `WouldBlock` without writing anything; retry later.
+    WouldBlock,
+}
+
+// Token bucket: fills at `rate` bytes per second up to one second's worth,
+// every write takes out its length. A record bigger than the bucket goes
+// through once the bucket is full and leaves it in debt.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+    mode: ThrottleMode,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(bytes_per_second: u64, mode: ThrottleMode) -> TokenBucket {
+        let rate = bytes_per_second.max(1) as f64;
+        TokenBucket {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+            mode,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    pub(crate) fn take(&mut self, bytes: u64) -> io::Result<()> {
+        let needed = bytes as f64;
+        let ready = needed.min(self.rate);
+        self.refill();
+        if self.tokens < ready {
+            match self.mode {
+                ThrottleMode::WouldBlock => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "write limit reached",
+                    ))
+                }
+                ThrottleMode::Sleep => {
+                    thread::sleep(Duration::from_secs_f64((ready - self.tokens) / self.rate));
+                    self.refill();
+                }
+            }
+        }
+        self.tokens -= needed;
+        Ok(())
+    }
+}
+
+impl<S: Storage> ActionKV<S> {
+    /// Caps `insert`, `insert_batch` and `commit_batch` at `bytes_per_second`
+    /// of records, allowing bursts of up to a second's worth. `None` lifts
+    /// the limit.
+    pub fn set_write_limit(&mut self, bytes_per_second: Option<u64>, mode: ThrottleMode) {
+        self.throttle = bytes_per_second.map(|rate| TokenBucket::new(rate, mode));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn limited_writes_take_their_time() {
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        store.set_write_limit(Some(100_000), ThrottleMode::Sleep);
+        let started = Instant::now();
+        for i in 0..150u32 {
+            store.insert(i.to_be_bytes(), &[7; 1000]).unwrap();
+        }
+        let elapsed = started.elapsed().as_secs_f64();
+
+        // the first second's worth goes out right away, the rest at the limit
+        let written = 150.0 * (HEADER_LEN as f64 + 4.0 + 1000.0);
+        assert!(elapsed >= (written - 100_000.0) / 100_000.0);
+    }
+
+    #[test]
+    fn would_block_mode_writes_nothing() {
+        let mut store = ActionKV::with_storage(Cursor::new(Vec::new()));
+        store.set_write_limit(Some(1000), ThrottleMode::WouldBlock);
+        store.insert("a", &[1; 900]).unwrap();
+        let len = store.f.get_ref().len();
+
+        let err = store.insert("b", &[2; 900]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(store.f.get_ref().len(), len);
+        assert!(!store.contains_key("b"));
+
+        store.set_write_limit(None, ThrottleMode::WouldBlock);
+        store.insert("b", &[2; 900]).unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+use libactionkv::{ActionKV, CrcVariant};
+use std::io::{BufRead, BufReader, Lines};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStderr, ChildStdout, Command, Stdio};
+
+fn temp_db(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("akv-{}-{}.db", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+// the follower, its stdout and its stderr, once it has found the end of
+// the file
+fn follow(path: &Path) -> (Child, Lines<BufReader<ChildStdout>>, Lines<BufReader<ChildStderr>>) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_akv_mem"))
+        .arg(path)
+        .arg("follow")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+    let banner = stderr.next().unwrap().unwrap();
+    assert!(banner.starts_with("following"));
+    (child, stdout, stderr)
+}
+
+#[test]
+fn follow_prints_appended_records() {
+    let path = temp_db("follow");
+    let mut store = ActionKV::open(&path).unwrap();
+    store.insert("old", b"skipped").unwrap();
+
+    let (mut child, mut lines, _) = follow(&path);
+    store.insert("new", b"hi").unwrap();
+    store.delete("old").unwrap();
+
+    let first = lines.next().unwrap().unwrap();
+    let second = lines.next().unwrap().unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert_eq!(first, format!("new {:?}", b"hi"));
+    assert_eq!(second, "old deleted");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn follow_moves_to_the_file_that_replaced_it() {
+    let path = temp_db("follow-compacted");
+    let mut store = ActionKV::open(&path).unwrap();
+    store.insert("old", b"1").unwrap();
+    store.insert("old", b"2").unwrap();
+
+    let (mut child, mut lines, mut notes) = follow(&path);
+    store.insert("before", b"hi").unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), format!("before {:?}", b"hi"));
+
+    // compaction renames a new file over the one being followed
+    store.compact().unwrap();
+    assert!(notes.next().unwrap().unwrap().starts_with("reopened"));
+    store.insert("after", b"ho").unwrap();
+    assert_eq!(lines.next().unwrap().unwrap(), format!("after {:?}", b"ho"));
+
+    // a replacement with other checksums is read with those
+    drop(store);
+    let other = temp_db("follow-castagnoli");
+    let mut replacement = ActionKV::open(&other).unwrap();
+    replacement.set_crc_variant(CrcVariant::Castagnoli);
+    replacement.insert("first", b"1").unwrap();
+    drop(replacement);
+    std::fs::rename(&other, &path).unwrap();
+    assert!(notes.next().unwrap().unwrap().starts_with("reopened"));
+    let mut store = ActionKV::open(&path).unwrap();
+    store.load().unwrap();
+    store.insert("second", b"2").unwrap();
+    let line = lines.next().unwrap().unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert_eq!(line, format!("second {:?}", b"2"));
+    std::fs::remove_file(&path).unwrap();
+}
@@ -13,117 +13,427 @@ static C: [u8; 11] = [116, 104, 97, 110, 107, 115, 102, 105, 115, 104, 0];
  * to write functions that only accept one of the two when only read-access
  * is required. As a workaround, use meta programming.
  */
+#[allow(dead_code)]
 fn is_strong<T: AsRef<str>>(password: T) -> bool {
     // this function can be called with &str and String
-    return password.as_ref().len() > 5;
+    password.as_ref().len() > 5
 }
 
 // Graphics application ---------------------------------------
-use graphics::math::{add, mul_scalar, Vec2d};
+mod quadtree;
+
+use graphics::math::{add, mul_scalar, sub, Vec2d};
 use piston_window::*;
+use quadtree::QuadTree;
 use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::alloc::{GlobalAlloc, Layout, System};
-use std::time::Instant;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 #[global_allocator]
-static ALLOCATOR: ReportingAllocator = ReportingAllocator;
-struct ReportingAllocator; // ?
+static ALLOCATOR: ReportingAllocator = ReportingAllocator::new();
+
+// one bucket per power of two, bucket n holding sizes up to 2^n bytes
+const SIZE_CLASSES: usize = usize::BITS as usize + 1;
+
+// smallest allocation in bytes worth reporting, read at the start of `main`
+const MIN_SIZE_VAR: &str = "ALLOC_MIN_BYTES";
+
+struct ReportingAllocator {
+    // atomics rather than anything that would itself need to allocate
+    counts: [AtomicU64; SIZE_CLASSES],
+    nanos: [AtomicU64; SIZE_CLASSES],
+    // allocations smaller than this go straight to `System`, unreported
+    min_size: AtomicUsize,
+    // lines written to stderr, one per reported allocation
+    logged: AtomicU64,
+}
+
+impl ReportingAllocator {
+    const fn new() -> ReportingAllocator {
+        ReportingAllocator {
+            counts: [const { AtomicU64::new(0) }; SIZE_CLASSES],
+            nanos: [const { AtomicU64::new(0) }; SIZE_CLASSES],
+            min_size: AtomicUsize::new(0),
+            logged: AtomicU64::new(0),
+        }
+    }
+
+    fn set_min_size(&self, bytes: usize) {
+        self.min_size.store(bytes, Ordering::Relaxed);
+    }
+
+    // the threshold from `MIN_SIZE_VAR`, if it is set; reading it allocates,
+    // so allocations made before this runs are all reported
+    fn min_size_from_env(&self) {
+        let value = match std::env::var(MIN_SIZE_VAR) {
+            Ok(value) => value,
+            Err(_) => return,
+        };
+        match value.trim().parse() {
+            Ok(bytes) => self.set_min_size(bytes),
+            Err(err) => eprintln!("ignoring {}={:?}: {}", MIN_SIZE_VAR, value, err),
+        }
+    }
+
+    // the per-allocation line: bytes requested, nanoseconds taken
+    fn log(&self, bytes: usize, time_taken: Duration) {
+        self.logged.fetch_add(1, Ordering::Relaxed);
+        eprintln!("{}\t{}", bytes, time_taken.as_nanos());
+    }
+
+    fn size_class(size: usize) -> usize {
+        size.next_power_of_two().trailing_zeros() as usize
+    }
+
+    // (largest size in the class, allocations, total nanoseconds) for every
+    // class that has seen an allocation, smallest first
+    fn by_size_class(&self) -> Vec<(u128, u64, u64)> {
+        (0..SIZE_CLASSES)
+            .map(|class| {
+                let count = self.counts[class].load(Ordering::Relaxed);
+                let nanos = self.nanos[class].load(Ordering::Relaxed);
+                (1u128 << class, count, nanos)
+            })
+            .filter(|&(_, count, _)| count > 0)
+            .collect()
+    }
+
+    fn dump_by_size_class(&self) {
+        eprintln!("{:>12}\t{:>10}\t{:>12}\t{:>8}", "up to bytes", "count", "total ns", "mean ns");
+        for (size, count, nanos) in self.by_size_class() {
+            eprintln!("{:>12}\t{:>10}\t{:>12}\t{:>8}", size, count, nanos, nanos / count);
+        }
+    }
+}
 
 unsafe impl GlobalAlloc for ReportingAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() < self.min_size.load(Ordering::Relaxed) {
+            return System.alloc(layout);
+        }
         let start = Instant::now();
         let ptr = System.alloc(layout);
         let end = Instant::now();
         let time_taken = end - start;
         let bytes_requested = layout.size();
 
-        eprintln!("{}\t{}", bytes_requested, time_taken.as_nanos());
-        return ptr;
+        let class = ReportingAllocator::size_class(bytes_requested);
+        self.counts[class].fetch_add(1, Ordering::Relaxed);
+        self.nanos[class].fetch_add(time_taken.as_nanos() as u64, Ordering::Relaxed);
+        self.log(bytes_requested, time_taken);
+        ptr
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         System.dealloc(ptr, layout);
     }
 }
 
+#[derive(Serialize, Deserialize)]
 struct World {
     current_turn: u64,
+    #[allow(clippy::vec_box)] // boxed on purpose, to exercise the allocator
     particles: Vec<Box<Particle>>,
     height: f64,
     width: f64,
-    rng: ThreadRng,
+    seed: u64,
+    #[serde(skip, default = "unseeded")]
+    rng: StdRng,
+    palette: Vec<[f32; 4]>,
+    // rgb colors a particle passes through as it fades, overriding those of
+    // its palette color; the particle's alpha keeps fading as before
+    color_gradient: Vec<[f32; 3]>,
+    spawn_mode: SpawnMode,
+    // smallest and largest side of new particles
+    size_range: (f64, f64),
+    shape: ParticleShape,
+    min_particles: usize,
+    max_particles: usize,
+    // gusts pushing every particle, off while the amplitude is 0
+    wind_amplitude: f64,
+    wind_frequency: f64,
+    // particles closer than the radius push each other apart
+    repulsion: f64,
+    interaction_radius: f64,
+    // pull towards the attractor, a push away from it when negative
+    attraction: f64,
+    // follows the mouse, nothing is attracted while it's `None`
+    #[serde(skip)]
+    attractor: Option<Vec2d<f64>>,
+    // particle positions as of the last `rebuild_tree`
+    #[serde(skip)]
+    tree: QuadTree,
+}
+
+// placeholder until World::load reseeds from the stored seed
+fn unseeded() -> StdRng {
+    StdRng::seed_from_u64(0)
 }
 
+// the rgb between `stops` for a particle faded to `alpha`: the first stop while
+// it's fully opaque, the last once it's about to die, evenly spaced between
+fn gradient_at(stops: &[[f32; 3]], alpha: f32) -> [f32; 3] {
+    let last = match stops.len().checked_sub(1) {
+        Some(last) => last,
+        None => return [WHITE[0], WHITE[1], WHITE[2]],
+    };
+    let age = ((1.0 - alpha) / (1.0 - DEAD_ALPHA)).clamp(0.0, 1.0);
+    let scaled = age * last as f32;
+    let i = (scaled.floor() as usize).min(last);
+    let (from, to) = (stops[i], stops[(i + 1).min(last)]);
+    let t = scaled - i as f32;
+    [0, 1, 2].map(|c| from[c] + (to[c] - from[c]) * t)
+}
+
+const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 0.99];
+
+// particles fainter than this are removed
+const DEAD_ALPHA: f32 = 0.02;
+
+// written on `s`, restored on `l`
+const SNAPSHOT: &str = "particles.bin";
+
+// strength of the mouse attractor, toggled with `a`
+const ATTRACTION: f64 = 2.0;
+
+// particles per digit of a burst: `1` fires 100 of them, `9` fires 900
+const BURST_PER_DIGIT: usize = 100;
+
+// font for the overlay shown with `--hud`
+const HUD_FONT: &str = "hud.ttf";
+
+// where new particles appear and which way they head off
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum SpawnMode {
+    // from the bottom edge, shooting up
+    Fountain,
+    // from the top edge, falling down
+    Rain,
+    // from the center, flying out in every direction
+    Explosion,
+}
+
+// how particles are drawn, toggled with `o`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum ParticleShape {
+    Rectangle,
+    Ellipse,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct Particle {
     height: f64,
     width: f64,
     position: Vec2d<f64>,
     velocity: Vec2d<f64>,
+    // from the net force of the last update, a = F / m
     acceleration: Vec2d<f64>,
+    mass: f64,
+    // forces applied since the last update
+    force: Vec2d<f64>,
+    // the launch push, weakening every update
+    thrust: Vec2d<f64>,
     color: [f32; 4],
 }
 
 impl Particle {
-    fn new(world: &World) -> Particle {
-        let mut rng = thread_rng();
-        let x = rng.gen_range(0.0..=world.width); // = -> right inclusive range
-        let y = world.height;
-        let x_velocity = 0.0;
-        let y_velocity = rng.gen_range(-2.0..0.0);
-        let x_acceleration = 0.0;
-        let y_acceleration = rng.gen_range(0.0..0.15);
-
-        return Particle {
-            height: 4.0,
-            width: 4.0,
-            position: [x, y].into(),
-            velocity: [x_velocity, y_velocity].into(),
-            acceleration: [x_acceleration, y_acceleration].into(),
-            color: [1.0, 1.0, 1.0, 0.99],
+    fn new(world: &mut World) -> Particle {
+        let rng = &mut world.rng;
+        let (position, velocity, thrust) = match world.spawn_mode {
+            SpawnMode::Fountain => {
+                let x = rng.gen_range(0.0..=world.width); // = -> right inclusive range
+                let y_velocity = rng.gen_range(-2.0..0.0);
+                let y_thrust = rng.gen_range(0.0..0.15);
+                ([x, world.height], [0.0, y_velocity], [0.0, y_thrust])
+            }
+            SpawnMode::Rain => {
+                let x = rng.gen_range(0.0..=world.width);
+                let y_velocity = rng.gen_range(0.5..2.0);
+                let y_thrust = rng.gen_range(0.0..0.05);
+                ([x, 0.0], [0.0, y_velocity], [0.0, y_thrust])
+            }
+            SpawnMode::Explosion => {
+                let angle = rng.gen_range(0.0..std::f64::consts::TAU);
+                let speed = rng.gen_range(0.5..3.0);
+                let center = [world.width / 2.0, world.height / 2.0];
+                (center, [speed * angle.cos(), speed * angle.sin()], [0.0, 0.0])
+            }
         };
+        let color = *world.palette.choose(rng).unwrap_or(&WHITE);
+        let (min_size, max_size) = world.size_range;
+        let size = rng.gen_range(min_size..=max_size.max(min_size));
+
+        Particle {
+            height: size,
+            width: size,
+            position,
+            velocity,
+            acceleration: [0.0, 0.0],
+            mass: 1.0,
+            force: [0.0, 0.0],
+            thrust,
+            color,
+        }
+    }
+    // faded out, or entirely outside a `width` x `height` window
+    fn is_dead(&self, width: f64, height: f64) -> bool {
+        let [x, y] = self.position;
+        self.color[3] < DEAD_ALPHA
+            || x + self.width < 0.0
+            || x > width
+            || y + self.height < 0.0
+            || y > height
+    }
+    fn apply_force(&mut self, force: Vec2d<f64>) {
+        self.force = add(self.force, force);
     }
     fn update(&mut self) {
+        self.apply_force(self.thrust);
+        self.thrust = mul_scalar(self.thrust, 0.7);
+        self.acceleration = mul_scalar(self.force, 1.0 / self.mass);
+        self.force = [0.0, 0.0];
+
         self.velocity = add(self.velocity, self.acceleration);
         self.position = add(self.position, self.velocity);
-        self.acceleration = mul_scalar(self.acceleration, 0.7);
         self.color[3] *= 0.995;
     }
 }
 
 impl World {
     fn new(width: f64, height: f64) -> World {
-        return World {
+        let seed = thread_rng().gen();
+        World {
             current_turn: 0,
             particles: Vec::<Box<Particle>>::new(),
-            height: height,
-            width: width,
-            rng: thread_rng(),
+            height,
+            width,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            palette: vec![WHITE],
+            color_gradient: Vec::new(),
+            spawn_mode: SpawnMode::Fountain,
+            size_range: (4.0, 4.0),
+            shape: ParticleShape::Rectangle,
+            min_particles: 0,
+            max_particles: usize::MAX,
+            wind_amplitude: 0.0,
+            wind_frequency: 0.0,
+            repulsion: 0.0,
+            interaction_radius: 0.0,
+            attraction: 0.0,
+            attractor: None,
+            tree: QuadTree::default(),
+        }
+    }
+    fn rebuild_tree(&mut self) {
+        self.tree = QuadTree::new([0.0, 0.0, self.width, self.height]);
+        for (i, particle) in self.particles.iter().enumerate() {
+            self.tree.insert(i, particle.position);
+        }
+    }
+
+    // indices into `particles` of those within `radius` of `position`, as of
+    // the last `rebuild_tree`
+    fn neighbors(&self, position: Vec2d<f64>, radius: f64) -> Vec<usize> {
+        let mut found = Vec::new();
+        self.tree.query(position, radius, &mut found);
+        found
+    }
+
+    // force of the attractor on a particle at `position`, pointing at the
+    // attractor and weakening with the inverse of the distance
+    fn attraction_at(&self, position: Vec2d<f64>) -> Vec2d<f64> {
+        let attractor = match self.attractor {
+            Some(attractor) if self.attraction != 0.0 => attractor,
+            _ => return [0.0, 0.0],
         };
+        let toward = sub(attractor, position);
+        // no slingshot for particles right on top of it
+        let distance = toward[0].hypot(toward[1]).max(1.0);
+        mul_scalar(toward, self.attraction / (distance * distance))
+    }
+
+    // pushes particles away from the ones right next to them
+    fn repel(&mut self) {
+        let radius = self.interaction_radius;
+        let pushes: Vec<Vec2d<f64>> = self
+            .particles
+            .iter()
+            .enumerate()
+            .map(|(i, particle)| {
+                let mut push = [0.0, 0.0];
+                for j in self.neighbors(particle.position, radius) {
+                    let away = sub(particle.position, self.particles[j].position);
+                    let distance = away[0].hypot(away[1]);
+                    if j != i && distance > 0.0 {
+                        let strength = self.repulsion * (1.0 - distance / radius) / distance;
+                        push = add(push, mul_scalar(away, strength));
+                    }
+                }
+                push
+            })
+            .collect();
+        for (particle, push) in self.particles.iter_mut().zip(pushes) {
+            particle.apply_force(push);
+        }
+    }
+    // a few detuned sines standing in for noise: the direction swirls
+    // around while the strength rises and falls, never past the amplitude
+    fn wind(&self) -> Vec2d<f64> {
+        let t = self.current_turn as f64 * self.wind_frequency;
+        let angle = (t.sin() + (t * 2.3 + 1.0).sin()) * std::f64::consts::PI;
+        let strength = self.wind_amplitude * (0.5 + 0.5 * (t * 0.7).sin());
+        [strength * angle.cos(), strength * angle.sin()]
     }
+    // spawns at most up to `max_particles`
     fn add_shapes(&mut self, n: i32) {
-        for _ in 0..n.abs() {
-            let particle = Particle::new(&self);
+        let room = self.max_particles.saturating_sub(self.particles.len());
+        for _ in 0..(n.unsigned_abs() as usize).min(room) {
+            let particle = Particle::new(self);
             let boxed_particle = Box::new(particle);
             self.particles.push(boxed_particle);
         }
     }
 
+    // spawns `n` particles in `mode` right away, leaving `spawn_mode` as it
+    // was; capped at `max_particles` like `add_shapes`
+    fn burst(&mut self, n: usize, mode: SpawnMode) {
+        let previous = std::mem::replace(&mut self.spawn_mode, mode);
+        self.add_shapes(n.min(i32::MAX as usize) as i32);
+        self.spawn_mode = previous;
+    }
+
+    // removes the oldest particles, at most down to `min_particles`
     fn remove_shapes(&mut self, n: i32) {
-        for _ in 0..n.abs() {
-            let mut to_delete = None;
-            let particle_iter = self.particles.iter().enumerate();
-            for (i, particle) in particle_iter {
-                if particle.color[3] < 0.02 {
-                    to_delete = Some(i);
-                }
-                break;
-            }
-            if let Some(i) = to_delete {
-                self.particles.remove(i);
-            } else {
-                self.particles.remove(0);
-            }
+        let spare = self.particles.len().saturating_sub(self.min_particles);
+        let n = (n.unsigned_abs() as usize).min(spare);
+        self.particles.drain(..n);
+    }
+    /// Writes the particles and settings, see `World::load`.
+    fn save<W: Write>(&self, w: W) -> bincode::Result<()> {
+        bincode::serialize_into(w, self)
+    }
+
+    /// Restores a world written by `save`. The RNG is reseeded from the
+    /// stored seed, so it starts over rather than picking up mid-sequence.
+    fn load<R: Read>(r: R) -> bincode::Result<World> {
+        let mut world: World = bincode::deserialize_from(r)?;
+        world.rng = StdRng::seed_from_u64(world.seed);
+        Ok(world)
+    }
+
+    fn resize(&mut self, width: f64, height: f64) {
+        self.width = width;
+        self.height = height;
+        for particle in &mut self.particles {
+            particle.position[0] = particle.position[0].clamp(0.0, width);
+            particle.position[1] = particle.position[1].clamp(0.0, height);
         }
     }
+
     fn update(&mut self) {
         let n = self.rng.gen_range(-3..=3);
 
@@ -133,14 +443,64 @@ impl World {
             self.remove_shapes(n);
         }
 
-        self.particles.shrink_to_fit();
-        for shape in &mut self.particles {
+        self.rebuild_tree();
+        if self.repulsion > 0.0 && self.interaction_radius > 0.0 {
+            self.repel();
+        }
+
+        let wind = self.wind();
+        let pulls: Vec<Vec2d<f64>> =
+            self.particles.iter().map(|p| self.attraction_at(p.position)).collect();
+        for (shape, pull) in self.particles.iter_mut().zip(pulls) {
+            shape.apply_force(add(wind, pull));
             shape.update();
+            if !self.color_gradient.is_empty() {
+                let [r, g, b] = gradient_at(&self.color_gradient, shape.color[3]);
+                shape.color = [r, g, b, shape.color[3]];
+            }
+        }
+        let (width, height) = (self.width, self.height);
+        self.particles.retain(|p| !p.is_dead(width, height));
+        if self.particles.len() < self.min_particles {
+            self.add_shapes((self.min_particles - self.particles.len()) as i32);
         }
+        self.particles.shrink_to_fit();
         self.current_turn += 1;
     }
 }
+// frames per second over the last `window` frames
+struct FpsCounter {
+    frames: VecDeque<Duration>,
+    window: usize,
+}
+
+impl FpsCounter {
+    fn new(window: usize) -> FpsCounter {
+        FpsCounter {
+            frames: VecDeque::with_capacity(window),
+            window: window.max(1),
+        }
+    }
+
+    // records how long the latest frame took
+    fn tick(&mut self, frame: Duration) {
+        if self.frames.len() == self.window {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+    }
+
+    fn fps(&self) -> f64 {
+        let total: Duration = self.frames.iter().sum();
+        if total.is_zero() {
+            return 0.0;
+        }
+        self.frames.len() as f64 / total.as_secs_f64()
+    }
+}
+
 fn main() {
+    ALLOCATOR.min_size_from_env();
     let a: usize = 42; // memory address size for the CPU
     let b: &[u8; 10] = &B;
     let c: Box<[u8]> = Box::new(C);
@@ -183,7 +543,7 @@ fn main() {
     // ------------------------------------------------------------------------
     let z: i64 = 42;
     let z_ptr = &z as *const i64;
-    let z_addr: usize = unsafe { std::mem::transmute(z_ptr) };
+    let z_addr: usize = z_ptr as usize;
     println!("z: {} ({:p} ...0x{:x})", z, z_ptr, z_addr + 7);
 
     // Graphics application ---------------------------------------
@@ -193,17 +553,436 @@ fn main() {
     ).exit_on_esc(true).build().expect("Could not create window");
 
     let mut world = World::new(width, height);
+    world.min_particles = 500;
+    world.max_particles = 2000;
+    world.wind_amplitude = 0.05;
+    world.wind_frequency = 0.01;
+    world.repulsion = 0.05;
+    world.interaction_radius = 6.0;
+    world.size_range = (2.0, 6.0);
     world.add_shapes(1000);
 
+    let mut glyphs = None;
+    if std::env::args().any(|arg| arg == "--hud") {
+        match window.load_font(HUD_FONT) {
+            Ok(font) => glyphs = Some(font),
+            Err(err) => eprintln!("no HUD, could not load {}: {}", HUD_FONT, err),
+        }
+    }
+    let mut fps = FpsCounter::new(60);
+    let mut last_frame = Instant::now();
+
     while let Some(event) = window.next() {
+        if event.render_args().is_some() {
+            let now = Instant::now();
+            fps.tick(now - last_frame);
+            last_frame = now;
+        }
+        if let Some(position) = event.mouse_cursor_args() {
+            world.attractor = Some(position);
+        }
+        if let Some(args) = event.resize_args() {
+            world.resize(args.window_size[0], args.window_size[1]);
+        }
+        match event.press_args() {
+            Some(Button::Keyboard(Key::S)) => {
+                let f = std::fs::File::create(SNAPSHOT).expect("Could not create snapshot");
+                world.save(f).expect("Could not save snapshot");
+            }
+            Some(Button::Keyboard(Key::L)) => {
+                if let Ok(f) = std::fs::File::open(SNAPSHOT) {
+                    world = World::load(f).expect("Could not load snapshot");
+                }
+            }
+            Some(Button::Keyboard(Key::F)) => world.spawn_mode = SpawnMode::Fountain,
+            Some(Button::Keyboard(Key::R)) => world.spawn_mode = SpawnMode::Rain,
+            Some(Button::Keyboard(Key::E)) => world.spawn_mode = SpawnMode::Explosion,
+            Some(Button::Keyboard(Key::O)) => {
+                world.shape = match world.shape {
+                    ParticleShape::Rectangle => ParticleShape::Ellipse,
+                    ParticleShape::Ellipse => ParticleShape::Rectangle,
+                }
+            }
+            // the mouse pulls, then pushes, then leaves the particles alone
+            Some(Button::Keyboard(Key::A)) => {
+                world.attraction = match world.attraction {
+                    a if a > 0.0 => -ATTRACTION,
+                    a if a < 0.0 => 0.0,
+                    _ => ATTRACTION,
+                }
+            }
+            Some(Button::Keyboard(key)) if (Key::D1..=Key::D9).contains(&key) => {
+                // 1 to 9 fire a burst of `BURST_PER_DIGIT` particles per digit
+                let digit = key as usize - Key::D0 as usize;
+                world.burst(digit * BURST_PER_DIGIT, world.spawn_mode);
+            }
+            _ => {}
+        }
         world.update();
 
-        window.draw_2d(&event, |ctx, renderer, _device| {
+        window.draw_2d(&event, |ctx, renderer, device| {
             clear([0.15, 0.17, 0.17, 0.9], renderer);
             for s in &mut world.particles {
                 let size = [s.position[0], s.position[1], s.width, s.height];
-                rectangle(s.color, size, ctx.transform, renderer);
+                match world.shape {
+                    ParticleShape::Rectangle => rectangle(s.color, size, ctx.transform, renderer),
+                    ParticleShape::Ellipse => ellipse(s.color, size, ctx.transform, renderer),
+                }
+            }
+            if let Some(glyphs) = &mut glyphs {
+                let lines = [
+                    format!("particles: {}", world.particles.len()),
+                    format!("fps: {:.0}", fps.fps()),
+                ];
+                for (i, line) in lines.iter().enumerate() {
+                    let at = ctx.transform.trans(10.0, 24.0 * (i + 1) as f64);
+                    text(WHITE, 16, line, glyphs, at, renderer).ok();
+                }
+                glyphs.factory.encoder.flush(device);
             }
         });
     }
+    ALLOCATOR.dump_by_size_class();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fps_counts_the_recent_frames() {
+        let mut fps = FpsCounter::new(10);
+        assert_eq!(fps.fps(), 0.0);
+        for _ in 0..10 {
+            fps.tick(Duration::from_millis(50));
+        }
+        assert!((fps.fps() - 20.0).abs() < 1e-9);
+
+        // slow frames from before the window no longer count
+        for _ in 0..10 {
+            fps.tick(Duration::from_millis(10));
+        }
+        assert!((fps.fps() - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn allocations_are_grouped_by_size_class() {
+        // a private allocator, so allocations elsewhere in the tests don't count
+        let allocator = ReportingAllocator::new();
+        let small = Layout::from_size_align(24, 8).unwrap();
+        let large = Layout::from_size_align(3000, 8).unwrap();
+        unsafe {
+            for _ in 0..3 {
+                allocator.dealloc(allocator.alloc(small), small);
+            }
+            allocator.dealloc(allocator.alloc(large), large);
+        }
+
+        let classes = allocator.by_size_class();
+        let counts: Vec<(u128, u64)> = classes.iter().map(|&(size, count, _)| (size, count)).collect();
+        assert_eq!(counts, vec![(32, 3), (4096, 1)]);
+    }
+
+    #[test]
+    fn allocations_below_the_minimum_size_go_unreported() {
+        let allocator = ReportingAllocator::new();
+        allocator.set_min_size(1024);
+        let layouts = [
+            Layout::from_size_align(24, 8).unwrap(),
+            Layout::from_size_align(1023, 8).unwrap(),
+            Layout::from_size_align(1024, 8).unwrap(),
+            Layout::from_size_align(3000, 8).unwrap(),
+        ];
+        unsafe {
+            for &layout in &layouts {
+                let ptr = allocator.alloc(layout);
+                assert!(!ptr.is_null());
+                allocator.dealloc(ptr, layout);
+            }
+        }
+
+        let classes = allocator.by_size_class();
+        let counts: Vec<(u128, u64)> = classes.iter().map(|&(size, count, _)| (size, count)).collect();
+        assert_eq!(counts, vec![(1024, 1), (4096, 1)]);
+        // a log line for each of the two reported, none for the others
+        assert_eq!(allocator.logged.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn particles_draw_colors_from_palette() {
+        let red = [1.0, 0.0, 0.0, 0.99];
+        let blue = [0.0, 0.0, 1.0, 0.99];
+        let mut world = World::new(100.0, 100.0);
+        world.palette = vec![red, blue];
+        world.add_shapes(50);
+
+        for p in &world.particles {
+            assert!(world.palette.contains(&p.color));
+        }
+    }
+
+    #[test]
+    fn wind_varies_within_amplitude() {
+        let mut world = World::new(100.0, 100.0);
+        assert_eq!(world.wind(), [0.0, 0.0]);
+
+        world.wind_amplitude = 0.5;
+        world.wind_frequency = 0.1;
+        let mut previous = world.wind();
+        for _ in 0..200 {
+            world.current_turn += 1;
+            let [x, y] = world.wind();
+            assert!((x * x + y * y).sqrt() <= 0.5 + 1e-9);
+            assert_ne!([x, y], previous);
+            previous = [x, y];
+        }
+    }
+
+    #[test]
+    fn neighbors_are_exactly_the_particles_in_range() {
+        let mut world = World::new(100.0, 100.0);
+        let positions = [[10.0, 10.0], [12.0, 10.0], [10.0, 14.9], [20.0, 20.0], [90.0, 90.0]];
+        world.add_shapes(positions.len() as i32);
+        for (particle, &position) in world.particles.iter_mut().zip(&positions) {
+            particle.position = position;
+        }
+        // enough extra particles far away to make the tree split
+        world.add_shapes(100);
+        for particle in &mut world.particles[positions.len()..] {
+            particle.position = [60.0 + particle.position[0] / 5.0, 60.0];
+        }
+        world.rebuild_tree();
+
+        let mut found = world.neighbors([10.0, 10.0], 5.0);
+        found.sort_unstable();
+        assert_eq!(found, vec![0, 1, 2]);
+        assert_eq!(world.neighbors([90.0, 90.0], 1.0), vec![4]);
+        assert!(world.neighbors([40.0, 40.0], 3.0).is_empty());
+    }
+
+    #[test]
+    fn forces_add_up_before_dividing_by_mass() {
+        let mut world = World::new(100.0, 100.0);
+        let mut particle = Particle::new(&mut world);
+        particle.mass = 2.0;
+        particle.velocity = [0.0, 0.0];
+        particle.thrust = [0.0, 0.0];
+
+        particle.apply_force([1.0, -2.0]);
+        particle.apply_force([3.0, 1.0]);
+        particle.update();
+        assert_eq!(particle.acceleration, [2.0, -0.5]);
+        assert_eq!(particle.velocity, [2.0, -0.5]);
+
+        // the accumulator starts over every update
+        particle.update();
+        assert_eq!(particle.acceleration, [0.0, 0.0]);
+    }
+
+    #[test]
+    fn fountain_shoots_up_from_the_bottom() {
+        let mut world = World::new(200.0, 100.0);
+        for _ in 0..100 {
+            let p = Particle::new(&mut world);
+            assert_eq!(p.position[1], 100.0);
+            assert!((0.0..=200.0).contains(&p.position[0]));
+            assert!(p.velocity[1] < 0.0);
+        }
+    }
+
+    #[test]
+    fn rain_falls_from_the_top() {
+        let mut world = World::new(200.0, 100.0);
+        world.spawn_mode = SpawnMode::Rain;
+        for _ in 0..100 {
+            let p = Particle::new(&mut world);
+            assert_eq!(p.position[1], 0.0);
+            assert!((0.0..=200.0).contains(&p.position[0]));
+            assert!(p.velocity[1] > 0.0);
+            assert_eq!(p.velocity[0], 0.0);
+        }
+    }
+
+    #[test]
+    fn explosion_flies_out_from_the_center() {
+        let mut world = World::new(200.0, 100.0);
+        world.spawn_mode = SpawnMode::Explosion;
+        let (mut left, mut up) = (false, false);
+        for _ in 0..100 {
+            let p = Particle::new(&mut world);
+            assert_eq!(p.position, [100.0, 50.0]);
+            let speed = p.velocity[0].hypot(p.velocity[1]);
+            assert!((0.5..3.0 + 1e-9).contains(&speed));
+            left |= p.velocity[0] < 0.0;
+            up |= p.velocity[1] < 0.0;
+        }
+        assert!(left && up);
+    }
+
+    #[test]
+    fn resize_clamps_particles_into_bounds() {
+        let mut world = World::new(200.0, 200.0);
+        world.add_shapes(50);
+        world.resize(50.0, 40.0);
+
+        assert_eq!((world.width, world.height), (50.0, 40.0));
+        for p in &world.particles {
+            assert!((0.0..=50.0).contains(&p.position[0]));
+            assert!((0.0..=40.0).contains(&p.position[1]));
+        }
+    }
+
+    #[test]
+    fn snapshot_round_trips_particles() {
+        let mut world = World::new(300.0, 200.0);
+        world.palette = vec![[0.5, 0.2, 0.1, 0.99], [0.1, 0.2, 0.5, 0.99]];
+        world.add_shapes(20);
+        world.update();
+
+        let mut buf = Vec::new();
+        world.save(&mut buf).unwrap();
+        let loaded = World::load(buf.as_slice()).unwrap();
+
+        assert_eq!(loaded.particles, world.particles);
+        assert_eq!(loaded.palette, world.palette);
+        assert_eq!(loaded.current_turn, world.current_turn);
+        assert_eq!((loaded.width, loaded.height), (world.width, world.height));
+        assert_eq!(loaded.seed, world.seed);
+    }
+
+    #[test]
+    fn particle_count_stays_within_bounds() {
+        let mut world = World::new(100.0, 100.0);
+        world.min_particles = 5;
+        world.max_particles = 20;
+        world.add_shapes(50);
+        assert_eq!(world.particles.len(), 20);
+
+        for _ in 0..1000 {
+            world.update();
+            assert!((5..=20).contains(&world.particles.len()));
+        }
+        world.remove_shapes(100);
+        assert_eq!(world.particles.len(), 5);
+    }
+
+    #[test]
+    fn attractor_pulls_harder_up_close() {
+        let mut world = World::new(100.0, 100.0);
+        world.attraction = 2.0;
+        assert_eq!(world.attraction_at([10.0, 50.0]), [0.0, 0.0]);
+        world.attractor = Some([50.0, 50.0]);
+
+        let near = world.attraction_at([40.0, 50.0]);
+        let far = world.attraction_at([10.0, 50.0]);
+        assert!(near[0] > 0.0 && far[0] > 0.0);
+        assert_eq!((near[1], far[1]), (0.0, 0.0));
+        assert!((near[0] - 4.0 * far[0]).abs() < 1e-12);
+        let below = world.attraction_at([50.0, 60.0]);
+        assert!(below[1] < 0.0 && below[0] == 0.0);
+
+        world.attraction = -2.0;
+        assert!(world.attraction_at([40.0, 50.0])[0] < 0.0);
+
+        // a particle left alone accelerates towards it
+        world.attraction = 2.0;
+        (world.min_particles, world.max_particles) = (1, 1);
+        world.add_shapes(1);
+        let particle = &mut world.particles[0];
+        particle.position = [10.0, 50.0];
+        particle.velocity = [0.0, 0.0];
+        particle.thrust = [0.0, 0.0];
+        world.update();
+        assert!(world.particles[0].acceleration[0] > 0.0);
+    }
+
+    #[test]
+    fn burst_adds_particles_up_to_the_cap() {
+        let mut world = World::new(100.0, 100.0);
+        world.max_particles = 30;
+        world.add_shapes(5);
+
+        world.burst(10, SpawnMode::Rain);
+        assert_eq!(world.particles.len(), 15);
+        assert!(world.particles[5..].iter().all(|p| p.velocity[1] > 0.0));
+        assert_eq!(world.spawn_mode, SpawnMode::Fountain);
+
+        world.burst(100, SpawnMode::Explosion);
+        assert_eq!(world.particles.len(), 30);
+    }
+
+    #[test]
+    fn particle_sizes_stay_within_the_range() {
+        let mut world = World::new(100.0, 100.0);
+        world.add_shapes(10);
+        assert!(world.particles.iter().all(|p| p.width == 4.0 && p.height == 4.0));
+
+        world.size_range = (2.0, 6.0);
+        world.particles.clear();
+        world.add_shapes(200);
+        for p in &world.particles {
+            assert!((2.0..=6.0).contains(&p.width));
+            assert_eq!(p.width, p.height);
+        }
+        let smallest = world.particles.iter().map(|p| p.width).fold(f64::MAX, f64::min);
+        let largest = world.particles.iter().map(|p| p.width).fold(0.0, f64::max);
+        assert!(largest - smallest > 1.0);
+    }
+
+    #[test]
+    fn particles_change_color_as_they_fade() {
+        let yellow = [1.0, 1.0, 0.0];
+        let red = [1.0, 0.0, 0.0];
+        let black = [0.0, 0.0, 0.0];
+        let close = |a: [f32; 3], b: [f32; 3]| a.iter().zip(&b).all(|(x, y)| (x - y).abs() < 1e-4);
+        let stops = [yellow, red, black];
+        assert!(close(gradient_at(&stops, 1.0), [1.0, 1.0, 0.0]));
+        assert!(close(gradient_at(&stops, 0.51), [1.0, 0.0, 0.0]));
+        assert!(close(gradient_at(&stops, DEAD_ALPHA), [0.0, 0.0, 0.0]));
+        assert!(close(gradient_at(&stops, 0.0), [0.0, 0.0, 0.0]));
+        assert!(close(gradient_at(&[red], 0.3), [1.0, 0.0, 0.0]));
+
+        let mut world = World::new(100.0, 100.0);
+        world.min_particles = 1;
+        world.max_particles = 1;
+        world.add_shapes(1);
+        world.particles[0].position = [50.0, 50.0];
+        world.particles[0].thrust = [0.0, 0.0];
+        world.update();
+        // no gradient, the palette color stays
+        assert_eq!(&world.particles[0].color[..3], &WHITE[..3]);
+
+        world.color_gradient = vec![yellow, red];
+        // halfway from opaque to dead after this update
+        world.particles[0].color[3] = (1.0 + DEAD_ALPHA) / 2.0 / 0.995;
+        world.update();
+        let [r, g, b, alpha] = world.particles[0].color;
+        assert!(close([r, g, b], [1.0, 0.5, 0.0]));
+        assert!((alpha - (1.0 + DEAD_ALPHA) / 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn dead_particles_are_removed_in_one_update() {
+        let (faded, gone, alive) = ([1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]);
+        let mut world = World::new(100.0, 100.0);
+        world.min_particles = 3;
+        world.max_particles = 3;
+        world.add_shapes(3);
+        world.particles[0].color = [faded[0], faded[1], faded[2], 0.01];
+        world.particles[1].color = [gone[0], gone[1], gone[2], 0.99];
+        world.particles[1].position = [-50.0, 50.0];
+        world.particles[2].color = [alive[0], alive[1], alive[2], 0.99];
+        world.particles[2].position = [50.0, 50.0];
+        world.particles[2].velocity = [0.0, 0.0];
+
+        world.update();
+        let rgb: Vec<[f32; 3]> = world
+            .particles
+            .iter()
+            .map(|p| [p.color[0], p.color[1], p.color[2]])
+            .collect();
+        assert!(!rgb.contains(&faded));
+        assert!(!rgb.contains(&gone));
+        assert!(rgb.contains(&alive));
+    }
 }
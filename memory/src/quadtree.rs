@@ -0,0 +1,85 @@
+use graphics::math::Vec2d;
+
+// points a node holds before it splits into quadrants
+const NODE_CAPACITY: usize = 8;
+
+// stops particles piled onto one spot from splitting nodes forever
+const MAX_DEPTH: usize = 10;
+
+/// Spatial index over particle positions, for finding the particles near a
+/// point without looking at all of them. Points outside the bounds are kept
+/// in the root, so they are still found.
+#[derive(Debug, Default)]
+pub struct QuadTree {
+    // x, y, width, height
+    bounds: [f64; 4],
+    points: Vec<(usize, Vec2d<f64>)>,
+    // none, or the four quadrants
+    children: Vec<QuadTree>,
+    depth: usize,
+}
+
+impl QuadTree {
+    pub fn new(bounds: [f64; 4]) -> QuadTree {
+        QuadTree {
+            bounds,
+            ..QuadTree::default()
+        }
+    }
+
+    pub fn insert(&mut self, id: usize, position: Vec2d<f64>) {
+        if let Some(child) = self.children.iter_mut().find(|c| c.contains(position)) {
+            child.insert(id, position);
+            return;
+        }
+        self.points.push((id, position));
+        if self.children.is_empty() && self.points.len() > NODE_CAPACITY && self.depth < MAX_DEPTH {
+            self.split();
+        }
+    }
+
+    /// Appends to `out` the ids of all points within `radius` of `center`.
+    pub fn query(&self, center: Vec2d<f64>, radius: f64, out: &mut Vec<usize>) {
+        for &(id, [x, y]) in &self.points {
+            let (dx, dy) = (x - center[0], y - center[1]);
+            if dx * dx + dy * dy <= radius * radius {
+                out.push(id);
+            }
+        }
+        for child in &self.children {
+            if child.touches(center, radius) {
+                child.query(center, radius, out);
+            }
+        }
+    }
+
+    fn contains(&self, [x, y]: Vec2d<f64>) -> bool {
+        let [left, top, width, height] = self.bounds;
+        x >= left && x < left + width && y >= top && y < top + height
+    }
+
+    // whether the circle overlaps our bounds at all
+    fn touches(&self, [x, y]: Vec2d<f64>, radius: f64) -> bool {
+        let [left, top, width, height] = self.bounds;
+        let dx = x - x.clamp(left, left + width);
+        let dy = y - y.clamp(top, top + height);
+        dx * dx + dy * dy <= radius * radius
+    }
+
+    fn split(&mut self) {
+        let [left, top, width, height] = self.bounds;
+        let (width, height) = (width / 2.0, height / 2.0);
+        let corners = [[left, top], [left + width, top], [left, top + height], [left + width, top + height]];
+        self.children = corners
+            .iter()
+            .map(|&[x, y]| QuadTree {
+                bounds: [x, y, width, height],
+                depth: self.depth + 1,
+                ..QuadTree::default()
+            })
+            .collect();
+        for (id, position) in std::mem::take(&mut self.points) {
+            self.insert(id, position);
+        }
+    }
+}